@@ -2,4 +2,4 @@
 set -e
 
 RUSTFLAGS='-C link-arg=-s' cargo build --target wasm32-unknown-unknown --release  \
-        && NEAR_ENV=mainnet near deploy --accountId archimarket.near --wasmFile target/wasm32-unknown-unknown/release/archimarket.wasm \
\ No newline at end of file
+        && NEAR_ENV=mainnet near deploy --accountId archimarket.near --wasmFile target/wasm32-unknown-unknown/release/archimarket.wasm --initFunction migrate --initArgs '{}' \
\ No newline at end of file
@@ -0,0 +1,96 @@
+//! Owner of the market and the roles the owner hands out for day to day operations.
+
+use crate::*;
+use near_sdk::require;
+use near_sdk::serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, BorshSerialize, BorshDeserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub enum Role {
+    /// sets fees and withdraws the earned ones
+    FeeManager,
+    /// pauses the market and halts pairs
+    Pauser,
+    /// lists and delists tokens
+    TokenListingManager,
+}
+
+impl Market {
+    pub(crate) fn assert_owner(&self) {
+        require!(env::predecessor_account_id() == self.owner_id, ERR04_PERMISSION_DENIED);
+    }
+
+    /// The owner holds every role.
+    pub(crate) fn assert_role(&self, role: Role) {
+        let account_id = env::predecessor_account_id();
+        require!(
+            account_id == self.owner_id || self.internal_has_role(&account_id, role),
+            ERR04_PERMISSION_DENIED
+        );
+    }
+
+    fn internal_has_role(&self, account_id: &AccountId, role: Role) -> bool {
+        self.roles
+            .get(account_id)
+            .is_some_and(|roles| roles.contains(&role))
+    }
+}
+
+#[near_bindgen]
+impl Market {
+    /// Starts the ownership transfer, it completes when `new_owner_id` calls `accept_ownership`.
+    #[payable]
+    pub fn transfer_ownership(&mut self, new_owner_id: AccountId) {
+        assert_one_yocto();
+        self.assert_owner();
+        self.pending_owner_id = Some(new_owner_id);
+    }
+
+    pub fn accept_ownership(&mut self) {
+        let account_id = env::predecessor_account_id();
+        require!(self.pending_owner_id.as_ref() == Some(&account_id), ERR04_PERMISSION_DENIED);
+
+        env::log_str(&format!("ownership transferred from {} to {}", self.owner_id, account_id));
+        self.owner_id = account_id;
+        self.pending_owner_id = None;
+    }
+
+    #[payable]
+    pub fn grant_role(&mut self, account_id: AccountId, role: Role) {
+        assert_one_yocto();
+        self.assert_owner();
+
+        let mut roles = self.roles.get(&account_id).unwrap_or_default();
+        if !roles.contains(&role) {
+            roles.push(role);
+            self.roles.insert(&account_id, &roles);
+        }
+    }
+
+    #[payable]
+    pub fn revoke_role(&mut self, account_id: AccountId, role: Role) {
+        assert_one_yocto();
+        self.assert_owner();
+
+        if let Some(mut roles) = self.roles.get(&account_id) {
+            roles.retain(|r| *r != role);
+            if roles.is_empty() {
+                self.roles.remove(&account_id);
+            } else {
+                self.roles.insert(&account_id, &roles);
+            }
+        }
+    }
+
+    pub fn get_owner(&self) -> AccountId {
+        self.owner_id.clone()
+    }
+
+    pub fn get_pending_owner(&self) -> Option<AccountId> {
+        self.pending_owner_id.clone()
+    }
+
+    pub fn get_roles(&self, account_id: AccountId) -> Vec<Role> {
+        self.roles.get(&account_id).unwrap_or_default()
+    }
+}
@@ -6,5 +6,21 @@ pub const ERR05_NOT_VALID_AMOUNT: &str = "E05: not valid amount";
 pub const ERR06_NOT_VALID_TOKEN: &str = "E06: not valid token";
 pub const ERR07_WRONG_MSG_FORMAT: &str = "E07: wrong msg format";
 pub const ERR08_NOT_CORRECT_PROMISE_RESULT_COUNT: &str = "E08: not correct promise result count";
+#[allow(dead_code)]
 pub const ERR09_DEPOSIT_FAILED: &str = "E09: deposit failed";
-pub const ERR10_NOT_ENOUGH: &str = "E10: not enough FT";
\ No newline at end of file
+pub const ERR10_NOT_ENOUGH: &str = "E10: not enough FT";
+pub const ERR11_DEPOSIT_MISMATCH: &str = "E11: deposited token or amount does not match the order";
+pub const ERR12_SLIPPAGE: &str = "E12: received amount is below min_receive";
+pub const ERR13_NOT_ENOUGH_STORAGE: &str = "E13: not enough storage deposit";
+pub const ERR14_STORAGE_IN_USE: &str = "E14: account still has orders or balances";
+pub const ERR15_ORDER_EXPIRED: &str = "E15: order expired";
+pub const ERR16_TRADING_HALTED: &str = "E16: trading is paused or halted for the pair";
+pub const ERR17_TOKEN_NOT_LISTED: &str = "E17: token is not listed";
+pub const ERR18_TOKEN_NOT_PROPOSED: &str = "E18: token is not waiting for approval";
+pub const ERR19_OPEN_LISTING_DISABLED: &str = "E19: open listing is disabled or the bond is too small";
+pub const ERR20_TOKEN_ALREADY_REGISTERED: &str = "E20: token is already registered";
+pub const ERR21_UNKNOWN_STATE_VERSION: &str = "E21: unknown state version";
+pub const ERR22_ORDER_SETTLING: &str = "E22: order has a settlement in progress";
+pub const ERR23_NOT_ENOUGH_GAS: &str = "E23: not enough gas attached for the settlement";
+pub const ERR24_ORDER_NOT_SETTLING: &str = "E24: order has no settlement in progress";
+pub const ERR25_TOKEN_PROPOSED: &str = "E25: token is waiting for approval, approve or slash it";
\ No newline at end of file
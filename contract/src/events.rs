@@ -0,0 +1,215 @@
+//! NEP-297 events, logged as `EVENT_JSON:{"standard":"archimarket","version":...}`.
+
+use crate::fees::FeeSplit;
+use crate::types::{Order, OrderId, OrderStatus};
+use near_sdk::json_types::U128;
+use near_sdk::serde::Serialize;
+use near_sdk::{env, serde_json, AccountId};
+
+pub const EVENT_STANDARD: &str = "archimarket";
+pub const EVENT_STANDARD_VERSION: &str = "1.0.0";
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+struct EventLog<'a, T: Serialize> {
+    standard: &'static str,
+    version: &'static str,
+    event: &'a str,
+    data: [T; 1],
+}
+
+fn emit<T: Serialize>(event: &str, data: T) {
+    let log = EventLog {
+        standard: EVENT_STANDARD,
+        version: EVENT_STANDARD_VERSION,
+        event,
+        data: [data],
+    };
+    let json = serde_json::to_string(&log).unwrap_or_else(|_| env::abort());
+    env::log_str(&format!("EVENT_JSON:{}", json));
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+struct OrderData<'a> {
+    order_id: &'a OrderId,
+    maker: &'a AccountId,
+    sell_token: &'a AccountId,
+    sell_amount: U128,
+    buy_token: &'a AccountId,
+    buy_amount: U128,
+    status: OrderStatus,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+struct OrderFilledData<'a> {
+    order_id: &'a OrderId,
+    maker: &'a AccountId,
+    taker: &'a AccountId,
+    sell_token: &'a AccountId,
+    sell_amount: U128,
+    buy_token: &'a AccountId,
+    buy_amount: U128,
+    remaining_sell_amount: U128,
+    remaining_buy_amount: U128,
+    is_partial: bool,
+    /// `Filled` once nothing is left, `Open` after a partial fill
+    status: OrderStatus,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+struct FeeData<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    order_id: Option<&'a OrderId>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    receiver: Option<&'a AccountId>,
+    token: &'a AccountId,
+    amount: U128,
+    /// sell amount of the fill the fee was taken from
+    #[serde(skip_serializing_if = "Option::is_none")]
+    gross_amount: Option<U128>,
+    /// part of the fill paid to the taker
+    #[serde(skip_serializing_if = "Option::is_none")]
+    net_amount: Option<U128>,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+struct SettlementFailedData<'a> {
+    order_id: &'a OrderId,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    maker: Option<&'a AccountId>,
+    taker: &'a AccountId,
+    /// token and amount returned to the taker
+    token: &'a AccountId,
+    amount: U128,
+}
+
+pub fn emit_order_created(order_id: &OrderId, order: &Order) {
+    emit(
+        "order_created",
+        OrderData {
+            order_id,
+            maker: &order.maker,
+            sell_token: &order.sell_token,
+            sell_amount: order.sell_amount,
+            buy_token: &order.buy_token,
+            buy_amount: order.buy_amount,
+            status: order.status,
+        },
+    );
+}
+
+/// `order` is the state after the fill, `sell_amount` and `buy_amount` are the filled part.
+pub fn emit_order_filled(
+    order_id: &OrderId,
+    order: &Order,
+    taker: &AccountId,
+    sell_amount: u128,
+    buy_amount: u128,
+) {
+    emit(
+        "order_filled",
+        OrderFilledData {
+            order_id,
+            maker: &order.maker,
+            taker,
+            sell_token: &order.sell_token,
+            sell_amount: U128(sell_amount),
+            buy_token: &order.buy_token,
+            buy_amount: U128(buy_amount),
+            remaining_sell_amount: order.sell_amount,
+            remaining_buy_amount: order.buy_amount,
+            is_partial: !order.is_filled(),
+            status: order.status,
+        },
+    );
+}
+
+/// `order` carries the amounts returned to the maker.
+pub fn emit_order_cancelled(order_id: &OrderId, order: &Order) {
+    emit(
+        "order_cancelled",
+        OrderData {
+            order_id,
+            maker: &order.maker,
+            sell_token: &order.sell_token,
+            sell_amount: order.sell_amount,
+            buy_token: &order.buy_token,
+            buy_amount: order.buy_amount,
+            status: order.status,
+        },
+    );
+}
+
+pub fn emit_fee_collected(order_id: &OrderId, token: &AccountId, split: &FeeSplit) {
+    emit(
+        "fee_collected",
+        FeeData {
+            order_id: Some(order_id),
+            receiver: None,
+            token,
+            amount: split.fee,
+            gross_amount: Some(split.gross),
+            net_amount: Some(split.net),
+        },
+    );
+}
+
+pub fn emit_fee_withdrawn(token: &AccountId, receiver: &AccountId, amount: u128) {
+    emit(
+        "fee_withdrawn",
+        FeeData {
+            order_id: None,
+            receiver: Some(receiver),
+            token,
+            amount: U128(amount),
+            gross_amount: None,
+            net_amount: None,
+        },
+    );
+}
+
+pub fn emit_settlement_failed(
+    order_id: &OrderId,
+    maker: Option<&AccountId>,
+    taker: &AccountId,
+    token: &AccountId,
+    amount: u128,
+) {
+    emit(
+        "settlement_failed",
+        SettlementFailedData {
+            order_id,
+            maker,
+            taker,
+            token,
+            amount: U128(amount),
+        },
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use near_sdk::test_utils::{get_logs, VMContextBuilder};
+    use near_sdk::testing_env;
+
+    #[test]
+    fn test_event_format() {
+        testing_env!(VMContextBuilder::new().build());
+
+        emit_fee_withdrawn(
+            &"usdt.near".parse().unwrap(),
+            &"treasury.near".parse().unwrap(),
+            1000,
+        );
+
+        assert_eq!(
+            get_logs(),
+            vec![r#"EVENT_JSON:{"standard":"archimarket","version":"1.0.0","event":"fee_withdrawn","data":[{"receiver":"treasury.near","token":"usdt.near","amount":"1000"}]}"#]
+        );
+    }
+}
@@ -1,6 +1,8 @@
-use near_sdk::{AccountId, ext_contract};
-use near_sdk::json_types::{U128, U64};
-use crate::OrderId;
+#![allow(clippy::too_many_arguments)]
+
+use near_sdk::ext_contract;
+use near_sdk::json_types::U128;
+use crate::{FillView, OrderId};
 
 #[ext_contract(ft_token)]
 pub trait FtToken {
@@ -12,28 +14,28 @@ pub trait FtToken {
     ) -> PromiseOrValue<U128>;
     fn ft_balance_of(&self, account_id: AccountId) -> U128;
     fn ft_total_supply(&self) -> U128;
+    fn ft_metadata(&self) -> FungibleTokenMetadata;
 }
 
 #[ext_contract(ext_self)]
 pub trait ExtSelf {
-    fn callback_on_send_tokens_to_maker(
+    fn callback_on_settle_fills(
         &self,
-        sender_id: AccountId,
-        sell_amount: U128,
+        taker: AccountId,
         sell_token: AccountId,
         buy_token: AccountId,
-        order_id: OrderId,
+        fills: Vec<FillView>,
     );
 
-    fn callback_after_deposit(
-        &self,
-        fee: U128,
-        sell_token: AccountId,
-        buy_token: AccountId,
-        order_id: OrderId
-    );
+    fn callback_on_taker_payout(&self, taker: AccountId, token: AccountId, payouts: Vec<(OrderId, U128)>);
+
+    fn callback_on_refund(&self, receiver: AccountId, token: AccountId, amount: U128);
+
+    fn callback_on_cancel_refund(&self, order_id: OrderId, maker: AccountId, token: AccountId, amount: U128);
 
     fn callback_on_send_tokens_to_ext_account(
         &self, token: AccountId, receiver: AccountId, amount: U128
     );
+
+    fn callback_on_ft_metadata(&mut self, token: AccountId) -> bool;
 }
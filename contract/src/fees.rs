@@ -0,0 +1,166 @@
+//! Trading fees. The fee is withheld from the sell token paid to the taker,
+//! every settlement records its gross amount, the fee and the net amount paid.
+
+use crate::access_control::Role;
+use crate::*;
+use near_sdk::serde::{Deserialize, Serialize};
+
+/// How the sell amount of a fill is split between the taker and the market.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct FeeSplit {
+    /// sell amount of the fill
+    pub gross: U128,
+    /// withheld by the market
+    pub fee: U128,
+    /// paid to the taker
+    pub net: U128,
+}
+
+impl FeeSplit {
+    /// Split of a fill of `gross` the taker was paid `net` of.
+    pub fn from_net(gross: u128, net: u128) -> Self {
+        let fee = gross
+            .checked_sub(net)
+            .unwrap_or_else(|| env::panic_str(ERR05_NOT_VALID_AMOUNT));
+        Self { gross: U128(gross), fee: U128(fee), net: U128(net) }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct FeeInfo {
+    /// in hundredths of a percent, `HUNDRED_PERCENT` is 100%
+    pub percent: u16,
+    /// withheld and not withdrawn yet
+    pub earned: U128,
+}
+
+impl Market {
+    pub(crate) fn get_or_create_fee_info(&mut self, sell_token: &AccountId) -> Fee {
+        match self.fees.get(sell_token) {
+            Some(fee) => fee,
+            None => {
+                let fee = Fee {
+                    // 1 / 100 = 0.01%
+                    // 100% = HUNDRED_PERCENT = 10000
+                    percent: 100,
+                    earned: 0
+                };
+
+                self.fees.insert(sell_token, &fee);
+                fee
+            }
+        }
+    }
+
+    /// Splits `amount` of `sell_token` into the fee and the net amount for the taker.
+    /// The net amount is rounded down, the rounding goes to the fee.
+    pub(crate) fn internal_split_fee(&mut self, amount: u128, sell_token: &AccountId) -> FeeSplit {
+        let fee_value = self.get_or_create_fee_info(sell_token).percent;
+        let net = mul_div(amount, (HUNDRED_PERCENT - fee_value) as u128, HUNDRED_PERCENT as u128);
+        FeeSplit::from_net(amount, net)
+    }
+
+    /// Adds the fee withheld by a settlement to the earned fees of the token.
+    pub(crate) fn internal_record_fee(&mut self, order_id: &OrderId, sell_token: &AccountId, split: FeeSplit) {
+        let mut fee_info = self.get_or_create_fee_info(sell_token);
+        fee_info.earned += split.fee.0;
+        self.fees.insert(sell_token, &fee_info);
+        emit_fee_collected(order_id, sell_token, &split);
+    }
+}
+
+#[near_bindgen]
+impl Market {
+    pub fn set_fee(&mut self, token: AccountId, percent: u16) {
+        self.assert_role(Role::FeeManager);
+        assert!(percent <= HUNDRED_PERCENT);
+        assert!(percent >= 1);
+
+        let earned = match self.fees.get(&token) {
+            Some(v) => v.earned,
+            None => 0
+        };
+
+        self.fees.insert(&token, &Fee::new(percent, earned));
+    }
+
+    /// Transfers `amount` of the fees earned in `token` to the `receiver`. The amount
+    /// is taken off `earned` right away, so concurrent withdrawals can't exceed it,
+    /// and is given back if the transfer fails.
+    #[payable]
+    pub fn transfer_earned_fees(
+        &mut self,
+        token: AccountId,
+        amount: u128,
+        receiver: AccountId
+    ) {
+        assert_one_yocto();
+        self.assert_role(Role::FeeManager);
+
+        let mut fee_info = self.fees.get(&token).expect(ERR10_NOT_ENOUGH);
+
+        if fee_info.earned == 0 {
+            env::panic_str("no need to transfer zero amount");
+        }
+
+        if amount > fee_info.earned {
+            env::panic_str(ERR10_NOT_ENOUGH);
+        }
+
+        fee_info.earned -= amount;
+        self.fees.insert(&token, &fee_info);
+
+        ft_token::ft_transfer(
+            receiver.clone(),
+            U128(amount),
+            "transfer from contract".to_string(),
+            token.clone(),
+            ONE_YOCTO,
+            FT_TRANSFER_TGAS
+        ).then(ext_self::callback_on_send_tokens_to_ext_account(
+            token,
+            receiver,
+            U128(amount),
+            env::current_account_id(),
+            0,
+            RESERVE_TGAS
+        ));
+    }
+
+    #[private]
+    pub fn callback_on_send_tokens_to_ext_account(
+        &mut self, token: AccountId, receiver: AccountId, amount: U128
+    ) {
+        assert_eq!(
+            env::promise_results_count(),
+            1,
+            "{}",
+            ERR08_NOT_CORRECT_PROMISE_RESULT_COUNT
+        );
+
+        match env::promise_result(0) {
+            PromiseResult::Failed => {
+                env::log_str(&format!("failed to transfer tokens to {}, returned to earned fees", receiver));
+
+                let mut fee_info = self.get_or_create_fee_info(&token);
+                fee_info.earned += amount.0;
+                self.fees.insert(&token, &fee_info);
+            },
+            PromiseResult::Successful(_) => {
+                env::log_str(&format!("tokens successfully transferred to {}", receiver));
+                emit_fee_withdrawn(&token, &receiver, amount.0);
+            }
+            _ => unreachable!()
+        }
+    }
+
+    /// `None` until a fee is set for the token or taken from it.
+    pub fn get_fee_info(&self, token: AccountId) -> Option<FeeInfo> {
+        self.fees.get(&token).map(|fee| FeeInfo {
+            percent: fee.percent,
+            earned: U128(fee.earned),
+        })
+    }
+}
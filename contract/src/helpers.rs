@@ -1,11 +1,50 @@
-use near_sdk::env;
-use crate::ERR04_PERMISSION_DENIED;
-
-pub fn assert_owner() {
-    assert_eq!(
-        env::current_account_id(),
-        env::predecessor_account_id(),
-        "{}",
-        ERR04_PERMISSION_DENIED
-    );
-}
\ No newline at end of file
+#[allow(clippy::all)]
+mod uint_types {
+    uint::construct_uint! {
+        pub struct U256(4);
+    }
+    uint::construct_uint! {
+        pub struct U512(8);
+    }
+}
+
+pub use uint_types::U256;
+use uint_types::U512;
+
+fn widen(value: U256) -> U512 {
+    let U256(words) = value;
+    U512([words[0], words[1], words[2], words[3], 0, 0, 0, 0])
+}
+
+/// `a * b / c` without overflowing on the intermediate product, rounded down.
+pub fn mul_div(a: u128, b: u128, c: u128) -> u128 {
+    (U256::from(a) * U256::from(b) / U256::from(c)).as_u128()
+}
+
+/// `a * b / c` without overflowing on the intermediate product, rounded up.
+pub fn mul_div_ceil(a: u128, b: u128, c: u128) -> u128 {
+    let c = U256::from(c);
+    ((U256::from(a) * U256::from(b) + c - 1) / c).as_u128()
+}
+
+pub fn gcd(mut a: u128, mut b: u128) -> u128 {
+    while b != 0 {
+        let r = a % b;
+        a = b;
+        b = r;
+    }
+    a
+}
+
+/// `num / den` as a decimal string with at most `decimals` fractional digits, rounded down.
+/// The fraction is scaled in 512 bits, `den` may take the whole `U256` range.
+pub fn format_decimal(num: U256, den: U256, decimals: u32) -> String {
+    let integer = num / den;
+    let fraction = widen(num % den) * U512::exp10(decimals as usize) / widen(den);
+    if fraction.is_zero() {
+        return integer.to_string();
+    }
+
+    let fraction = format!("{:0>width$}", fraction.to_string(), width = decimals as usize);
+    format!("{}.{}", integer, fraction.trim_end_matches('0'))
+}
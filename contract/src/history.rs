@@ -0,0 +1,264 @@
+//! Recent trades and OHLCV candles of every pair, kept on chain for the bot and the charts.
+
+use crate::*;
+use near_sdk::collections::Vector;
+use near_sdk::serde::{Deserialize, Serialize};
+use std::ops::Bound;
+
+/// Trades kept per pair, older ones are overwritten.
+pub const MAX_TRADES_PER_PAIR: u64 = 100;
+/// Candles kept per pair and resolution, the oldest ones are dropped.
+pub const MAX_CANDLES: u64 = 720;
+
+const HOUR_NS: u64 = 3600 * 1_000_000_000;
+
+/// Side of the taker in a trade, relative to the base token of the pair.
+#[derive(Serialize, Deserialize, BorshSerialize, BorshDeserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub enum Side {
+    Buy,
+    Sell,
+}
+
+/// A fill of a resting order in the terms of its pair "base#quote", where the
+/// base token is the lower account id of the two. `price` is the exact ratio of
+/// `quote_amount` per `base_amount`, so higher prices order first like in `Price`.
+#[derive(Serialize, Deserialize, BorshSerialize, BorshDeserialize, Clone, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Trade {
+    pub order_id: OrderId,
+    pub maker: AccountId,
+    pub taker: AccountId,
+    pub side: Side,
+    pub base_amount: U128,
+    pub quote_amount: U128,
+    pub price: Price,
+    pub timestamp: U64,
+}
+
+/// Key of the market of two tokens, the same for both directions of the orders.
+pub fn pair_key(token_a: &AccountId, token_b: &AccountId) -> String {
+    if token_a <= token_b {
+        compose_key(token_a, token_b)
+    } else {
+        compose_key(token_b, token_a)
+    }
+}
+
+/// `pair` with its tokens put in the `pair_key` order.
+fn normalize_pair(pair: String) -> String {
+    match pair.split_once('#') {
+        Some((token_a, token_b)) if token_a > token_b => format!("{}#{}", token_b, token_a),
+        _ => pair,
+    }
+}
+
+/// Ring buffer of the last `MAX_TRADES_PER_PAIR` trades of a pair.
+#[derive(BorshSerialize, BorshDeserialize)]
+pub struct TradeLog {
+    trades: Vector<Trade>,
+    /// number of trades ever recorded, the next one goes to `total % MAX_TRADES_PER_PAIR`
+    total: u64,
+}
+
+impl TradeLog {
+    fn new(pair: &str) -> Self {
+        Self {
+            trades: Vector::new(StorageKey::PairTrades { pair: pair.to_string() }),
+            total: 0,
+        }
+    }
+
+    fn push(&mut self, trade: &Trade) {
+        if self.trades.len() < MAX_TRADES_PER_PAIR {
+            self.trades.push(trade);
+        } else {
+            self.trades.replace(self.total % MAX_TRADES_PER_PAIR, trade);
+        }
+        self.total += 1;
+    }
+
+    /// The `index`-th trade counting back from the newest one.
+    fn get_from_newest(&self, index: u64) -> Option<Trade> {
+        if index >= self.trades.len() {
+            return None;
+        }
+        self.trades.get((self.total - 1 - index) % MAX_TRADES_PER_PAIR)
+    }
+}
+
+#[derive(Serialize, Deserialize, BorshSerialize, BorshDeserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub enum Resolution {
+    #[serde(rename = "1h")]
+    Hour,
+    #[serde(rename = "1d")]
+    Day,
+}
+
+impl Resolution {
+    pub const ALL: [Resolution; 2] = [Resolution::Hour, Resolution::Day];
+
+    fn duration(&self) -> u64 {
+        match self {
+            Resolution::Hour => HOUR_NS,
+            Resolution::Day => 24 * HOUR_NS,
+        }
+    }
+
+    /// Start of the candle the `timestamp` falls into.
+    fn candle_start(&self, timestamp: u64) -> u64 {
+        timestamp - timestamp % self.duration()
+    }
+}
+
+/// Trades of a pair over one period. Prices are quote token per base token like in `Trade`,
+/// volumes are the traded amounts of both tokens.
+#[derive(Serialize, Deserialize, BorshSerialize, BorshDeserialize, Clone, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Candle {
+    pub start: U64,
+    pub open: Price,
+    pub high: Price,
+    pub low: Price,
+    pub close: Price,
+    pub base_volume: U128,
+    pub quote_volume: U128,
+}
+
+impl Candle {
+    fn new(start: u64, trade: &Trade) -> Self {
+        Self {
+            start: U64(start),
+            open: trade.price,
+            high: trade.price,
+            low: trade.price,
+            close: trade.price,
+            base_volume: trade.base_amount,
+            quote_volume: trade.quote_amount,
+        }
+    }
+
+    fn add(&mut self, trade: &Trade) {
+        // a higher quote/base ratio orders first
+        self.high = std::cmp::min(self.high, trade.price);
+        self.low = std::cmp::max(self.low, trade.price);
+        self.close = trade.price;
+        self.base_volume = U128(self.base_volume.0 + trade.base_amount.0);
+        self.quote_volume = U128(self.quote_volume.0 + trade.quote_amount.0);
+    }
+}
+
+impl Market {
+    /// Records the fill of `sell_amount` of the maker's `order` for `buy_amount`.
+    pub(crate) fn internal_record_fill(
+        &mut self,
+        order_id: OrderId,
+        order: &Order,
+        taker: &AccountId,
+        sell_amount: u128,
+        buy_amount: u128,
+    ) {
+        // the maker selling the base token means the taker bought it
+        let (side, base_amount, quote_amount) = if order.sell_token < order.buy_token {
+            (Side::Buy, sell_amount, buy_amount)
+        } else {
+            (Side::Sell, buy_amount, sell_amount)
+        };
+        self.internal_record_trade(
+            &pair_key(&order.sell_token, &order.buy_token),
+            Trade {
+                order_id,
+                maker: order.maker.clone(),
+                taker: taker.clone(),
+                side,
+                base_amount: U128(base_amount),
+                quote_amount: U128(quote_amount),
+                price: Price::new(quote_amount, base_amount),
+                timestamp: U64(env::block_timestamp()),
+            },
+        );
+    }
+
+    pub(crate) fn internal_record_trade(&mut self, pair: &str, trade: Trade) {
+        for resolution in Resolution::ALL {
+            self.internal_update_candle(pair, resolution, &trade);
+        }
+
+        let mut log = self.trades.get(&pair.to_string()).unwrap_or_else(|| TradeLog::new(pair));
+        log.push(&trade);
+        self.trades.insert(&pair.to_string(), &log);
+    }
+
+    fn internal_update_candle(&mut self, pair: &str, resolution: Resolution, trade: &Trade) {
+        let key = (pair.to_string(), resolution);
+        let mut candles = self.candles.get(&key).unwrap_or_else(|| {
+            TreeMap::new(StorageKey::PairCandles { pair: pair.to_string(), resolution })
+        });
+
+        let start = resolution.candle_start(trade.timestamp.0);
+        let candle = match candles.get(&start) {
+            Some(mut candle) => {
+                candle.add(trade);
+                candle
+            }
+            None => Candle::new(start, trade),
+        };
+        candles.insert(&start, &candle);
+
+        if candles.len() > MAX_CANDLES {
+            if let Some(oldest) = candles.min() {
+                candles.remove(&oldest);
+            }
+        }
+        self.candles.insert(&key, &candles);
+    }
+}
+
+#[near_bindgen]
+impl Market {
+    /// Recent trades of the `pair` ("base#quote", either order of the tokens), newest first.
+    /// `from_index` counts back from the newest trade.
+    pub fn get_trades(&self, pair: String, from_index: Option<u64>, limit: Option<u64>) -> Vec<Trade> {
+        let log = match self.trades.get(&normalize_pair(pair)) {
+            Some(log) => log,
+            None => return vec![],
+        };
+
+        let from_index = from_index.unwrap_or(0);
+        let limit = limit.unwrap_or(DEFAULT_ORDERS_LIMIT);
+        (from_index..from_index.saturating_add(limit))
+            .map_while(|index| log.get_from_newest(index))
+            .collect()
+    }
+
+    /// Candles of the `pair` at the `resolution` ("1h" or "1d"), oldest first, starting
+    /// with the one `from_ts` (nanoseconds) falls into. Periods without trades have no candle.
+    pub fn get_candles(
+        &self,
+        pair: String,
+        resolution: Resolution,
+        from_ts: Option<U64>,
+        limit: Option<u64>,
+    ) -> Vec<Candle> {
+        let candles = match self.candles.get(&(normalize_pair(pair), resolution)) {
+            Some(candles) => candles,
+            None => return vec![],
+        };
+
+        let from = resolution.candle_start(from_ts.map_or(0, |from_ts| from_ts.0));
+        candles
+            .range((Bound::Included(from), Bound::Unbounded))
+            .take(limit.unwrap_or(DEFAULT_CANDLES_LIMIT) as usize)
+            .map(|(_, candle)| candle)
+            .collect()
+    }
+
+    /// Price of the last trade of the `pair`, quote token per base token.
+    pub fn get_last_price(&self, pair: String) -> Option<Price> {
+        self.trades
+            .get(&normalize_pair(pair))
+            .and_then(|log| log.get_from_newest(0))
+            .map(|trade| trade.price)
+    }
+}
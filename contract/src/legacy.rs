@@ -0,0 +1,119 @@
+//! Storage layouts of older contract versions, only read by the migrations.
+
+use crate::types::{Fee, Order, OrderStatus};
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::collections::{LookupMap, UnorderedMap, Vector};
+use near_sdk::json_types::{U128, U64};
+use near_sdk::AccountId;
+
+/// State of version 1, before storage management, the order index and the token registry.
+#[derive(BorshSerialize, BorshDeserialize)]
+pub struct MarketV1 {
+    pub version: u8,
+    pub orders: UnorderedMap<String, LegacyOrderBook<OrderV1>>,
+    pub order_id_to_order: LookupMap<LegacyOrderId, OrderV1>,
+    pub fees: LookupMap<AccountId, Fee>,
+}
+
+/// `Order` of version 1, without partial fills and expiration.
+#[derive(Clone, Debug, PartialEq, BorshSerialize, BorshDeserialize)]
+pub struct OrderV1 {
+    pub maker: AccountId,
+    pub sell_token: AccountId,
+    pub sell_amount: U128,
+    pub buy_token: AccountId,
+    pub buy_amount: U128,
+}
+
+impl From<OrderV1> for Order {
+    fn from(order: OrderV1) -> Self {
+        Order {
+            maker: order.maker,
+            sell_token: order.sell_token,
+            sell_amount: order.sell_amount,
+            buy_token: order.buy_token,
+            buy_amount: order.buy_amount,
+            filled_sell_amount: U128(0),
+            filled_buy_amount: U128(0),
+            expires_at: None,
+            status: OrderStatus::Open,
+            // placed before storage management, never charged
+            storage_bytes: 0,
+        }
+    }
+}
+
+/// `OrderId` of the orders placed before prices were exact: integer price key and hash or nonce.
+#[derive(Debug, Ord, PartialOrd, Eq, PartialEq, Clone, Copy, BorshSerialize, BorshDeserialize)]
+pub struct LegacyOrderId(pub u128, pub u64);
+
+/// `Order` of version 2, before the settlement status.
+#[derive(Clone, Debug, PartialEq, BorshSerialize, BorshDeserialize)]
+pub struct OrderV2 {
+    pub maker: AccountId,
+    pub sell_token: AccountId,
+    pub sell_amount: U128,
+    pub buy_token: AccountId,
+    pub buy_amount: U128,
+    pub filled_sell_amount: U128,
+    pub filled_buy_amount: U128,
+    pub expires_at: Option<U64>,
+}
+
+impl From<OrderV2> for Order {
+    fn from(order: OrderV2) -> Self {
+        Order {
+            maker: order.maker,
+            sell_token: order.sell_token,
+            sell_amount: order.sell_amount,
+            buy_token: order.buy_token,
+            buy_amount: order.buy_amount,
+            filled_sell_amount: order.filled_sell_amount,
+            filled_buy_amount: order.filled_buy_amount,
+            expires_at: order.expires_at,
+            status: OrderStatus::Open,
+            // placed before storage management, never charged
+            storage_bytes: 0,
+        }
+    }
+}
+
+/// Same layout as a `TreeMap` node.
+#[derive(BorshSerialize, BorshDeserialize)]
+pub struct LegacyNode {
+    pub id: u64,
+    pub key: LegacyOrderId,
+    pub lft: Option<u64>,
+    pub rgt: Option<u64>,
+    pub ht: u64,
+}
+
+/// Same layout as `TreeMap<LegacyOrderId, V>`. The nodes are read straight
+/// from the vector, the tree itself was balanced with the old `OrderId` ordering
+/// and can't be searched reliably.
+#[derive(BorshSerialize, BorshDeserialize)]
+pub struct LegacyOrderBook<V> {
+    pub root: u64,
+    pub val: LookupMap<LegacyOrderId, V>,
+    pub tree: Vector<LegacyNode>,
+}
+
+impl<V: BorshSerialize + BorshDeserialize> LegacyOrderBook<V> {
+    /// Removes all orders from storage and returns them in the old key order.
+    pub fn drain(&mut self) -> Vec<(LegacyOrderId, V)> {
+        let mut orders: Vec<(LegacyOrderId, V)> = self
+            .tree
+            .iter()
+            .filter_map(|node| self.val.get(&node.key).map(|order| (node.key, order)))
+            .collect();
+        orders.sort_by_key(|(order_id, _)| *order_id);
+
+        for (order_id, _) in orders.iter() {
+            self.val.remove(order_id);
+        }
+        self.tree.clear();
+        self.root = 0;
+
+        orders
+    }
+}
@@ -1,31 +1,66 @@
 use crate::ext_interfaces::*;
 use crate::types::*;
 use crate::helpers::*;
+use crate::events::*;
+use crate::storage::AccountStorage;
+use crate::access_control::Role;
+use crate::tokens::{TokenInfo, TokenMetadata};
+use crate::history::{Candle, Resolution, TradeLog};
 use errors::*;
 
 use near_contract_standards::fungible_token::receiver::FungibleTokenReceiver;
 use near_sdk::borsh;
 use near_sdk::borsh::{BorshDeserialize, BorshSerialize};
-use near_sdk::collections::{LookupMap, TreeMap, UnorderedMap};
-use near_sdk::json_types::U128;
-use near_sdk::json_types::U64;
+use near_sdk::collections::{LookupMap, TreeMap, UnorderedMap, UnorderedSet};
+use near_sdk::json_types::{U128, U64};
 use near_sdk::near_bindgen;
 use near_sdk::serde_json;
 use near_sdk::BorshStorageKey;
 use near_sdk::Gas;
 use near_sdk::PanicOnDefault;
 use near_sdk::PromiseResult;
-use near_sdk::{env, AccountId, PromiseOrValue};
+use near_sdk::{assert_one_yocto, env, require, AccountId, Balance, Promise, PromiseOrValue};
 
 mod errors;
 mod ext_interfaces;
 mod types;
 mod helpers;
-
+mod storage;
+mod events;
+mod legacy;
+mod history;
+mod access_control;
+mod pause;
+mod tokens;
+mod migration;
+mod fees;
+
+/// Layout version of `Market`, bumped with every change `migrate` has to convert.
+pub const STATE_VERSION: u8 = 3;
 pub const ONE_YOCTO: u128 = 1;
 pub const HUNDRED_PERCENT: u16 = 10000;
 pub const FT_TRANSFER_TGAS: Gas = Gas(50_000_000_000_000);
 pub const RESERVE_TGAS: Gas = Gas(15_000_000_000_000);
+/// Gas for one transfer of a settlement, a NEP-141 `ft_transfer` takes a few TGas.
+pub const SETTLE_TRANSFER_TGAS: Gas = Gas(10_000_000_000_000);
+/// Gas for the bookkeeping of one fill: locking the order, filling it, recording the trade.
+pub const FILL_TGAS: Gas = Gas(10_000_000_000_000);
+/// Gas `callback_on_settle_fills` needs besides its fills: the payout to the taker
+/// and the refund of failed fills, both with their callbacks.
+pub const SETTLE_CALLBACK_TGAS: Gas = Gas(RESERVE_TGAS.0 + 2 * (SETTLE_TRANSFER_TGAS.0 + RESERVE_TGAS.0));
+/// Gas one more fill settled by transfers needs: the payment to the maker,
+/// locking the order here and filling it in the callback.
+pub const MATCH_TGAS: Gas = Gas(SETTLE_TRANSFER_TGAS.0 + 2 * FILL_TGAS.0);
+/// Gas a refund needs: the transfer and `callback_on_refund`.
+pub const REFUND_TGAS: Gas = Gas(FT_TRANSFER_TGAS.0 + RESERVE_TGAS.0);
+/// Gas for removing one order in a batch cancel.
+pub const CANCEL_ORDER_TGAS: Gas = Gas(5_000_000_000_000);
+pub const DEFAULT_CLEANUP_LIMIT: u64 = 20;
+pub const DEFAULT_ORDERS_LIMIT: u64 = 5;
+pub const DEFAULT_BOOK_DEPTH: u64 = 10;
+pub const DEFAULT_CANDLES_LIMIT: u64 = 100;
+/// Fractional digits of the prices in the order book view.
+pub const PRICE_DECIMALS: u32 = 18;
 
 #[derive(BorshSerialize, BorshStorageKey)]
 pub enum StorageKey {
@@ -33,7 +68,20 @@ pub enum StorageKey {
     MapByOrderId,
     Orders,
     OrderIdToOrder,
-    FeesByAccountIds
+    FeesByAccountIds,
+    Balances,
+    AccountBalances { account_id: AccountId },
+    StorageAccounts,
+    OrdersByAccount,
+    AccountOrders { account_id: AccountId },
+    Trades,
+    PairTrades { pair: String },
+    Candles,
+    PairCandles { pair: String, resolution: Resolution },
+    Roles,
+    HaltedPairs,
+    Tokens,
+    TokenMetadata,
 }
 
 #[near_bindgen]
@@ -42,7 +90,33 @@ pub struct Market {
     version: u8,
     orders: UnorderedMap<String, TreeMap<OrderId, Order>>,
     order_id_to_order: LookupMap<OrderId, Order>,
-    fees: LookupMap<AccountId, Fee>
+    fees: LookupMap<AccountId, Fee>,
+    /// account -> token -> amount the contract holds for the account
+    balances: UnorderedMap<AccountId, UnorderedMap<AccountId, u128>>,
+    /// NEP-145 storage deposits paying for the orders of each account
+    storage_accounts: LookupMap<AccountId, AccountStorage>,
+    /// nonce of the next `OrderId`, only ever grows
+    next_order_nonce: u64,
+    /// open orders of every maker
+    orders_by_account: LookupMap<AccountId, UnorderedSet<OrderId>>,
+    /// recent trades of every pair
+    trades: LookupMap<String, TradeLog>,
+    /// OHLCV candles of every pair and resolution by their start timestamp
+    candles: LookupMap<(String, Resolution), TreeMap<u64, Candle>>,
+    owner_id: AccountId,
+    /// set by `transfer_ownership` until the new owner accepts
+    pending_owner_id: Option<AccountId>,
+    roles: LookupMap<AccountId, Vec<Role>>,
+    /// stops new orders and matches in all pairs
+    paused: bool,
+    /// pairs with trading stopped in both directions
+    halted_pairs: UnorderedSet<String>,
+    /// tokens allowed to be traded
+    tokens: UnorderedMap<AccountId, TokenInfo>,
+    /// bond for proposing a token, open listing is disabled while `None`
+    listing_bond: Option<Balance>,
+    /// cached NEP-148 metadata of registered tokens
+    token_metadata: LookupMap<AccountId, TokenMetadata>,
 }
 
 #[near_bindgen]
@@ -60,16 +134,34 @@ impl FungibleTokenReceiver for Market {
         ));
         env::log_str(&format!("transfer msg: {}", msg));
         if msg.is_empty() {
-            return PromiseOrValue::Value(amount);
+            // plain deposit to the internal balance, only kept for listed tokens of
+            // registered accounts, the rest goes back to the sender
+            if self.storage_accounts.get(&sender_id).is_none() {
+                env::log_str(ERR13_NOT_ENOUGH_STORAGE);
+                return PromiseOrValue::Value(amount);
+            }
+            if !self.is_token_listed(&token) {
+                env::log_str(ERR17_TOKEN_NOT_LISTED);
+                return PromiseOrValue::Value(amount);
+            }
+            self.internal_deposit(&sender_id, &token, amount.0);
+            PromiseOrValue::Value(U128(0))
         } else {
             let message =
                 serde_json::from_str::<TokenReceiverMessage>(&msg).expect(ERR07_WRONG_MSG_FORMAT);
+            if self.internal_is_message_halted(&message, &token) {
+                // the whole deposit goes back to the sender
+                env::log_str(ERR16_TRADING_HALTED);
+                return PromiseOrValue::Value(amount);
+            }
+
             match message {
                 TokenReceiverMessage::NewOrderAction {
                     sell_token,
                     sell_amount,
                     buy_token,
                     buy_amount,
+                    expires_at,
                 } => {
                     env::log_str("its new_order_action");
 
@@ -78,16 +170,23 @@ impl FungibleTokenReceiver for Market {
                         sell_amount,
                         buy_token,
                         buy_amount,
+                        expires_at,
                     };
 
-                    self.add_order(new_order_action, sender_id);
-                    return PromiseOrValue::Value(U128(0));
+                    let excess = self.add_order(new_order_action, sender_id, token, amount);
+                    PromiseOrValue::Value(excess)
                 }
                 TokenReceiverMessage::Match { order_id } => {
                     env::log_str("its order match ");
 
                     self.match_order(sender_id, order_id, amount, token);
-                    return PromiseOrValue::Value(U128(0));
+                    PromiseOrValue::Value(U128(0))
+                }
+                TokenReceiverMessage::MarketBuy { buy_token, min_receive } => {
+                    env::log_str("its market buy");
+
+                    let unspent = self.market_buy(sender_id, token, amount, buy_token, min_receive);
+                    PromiseOrValue::Value(unspent)
                 }
             }
         }
@@ -96,15 +195,29 @@ impl FungibleTokenReceiver for Market {
 
 #[near_bindgen]
 impl Market {
+    /// `owner_id` defaults to the contract account.
     #[init]
-    pub fn new(version: u8) -> Self {
-        let this = Self {
-            version,
+    pub fn new(owner_id: Option<AccountId>) -> Self {
+        Self {
+            version: STATE_VERSION,
             orders: UnorderedMap::new(StorageKey::Orders),
             order_id_to_order: LookupMap::new(StorageKey::OrderIdToOrder),
-            fees: LookupMap::new(StorageKey::FeesByAccountIds)
-        };
-        this
+            fees: LookupMap::new(StorageKey::FeesByAccountIds),
+            balances: UnorderedMap::new(StorageKey::Balances),
+            storage_accounts: LookupMap::new(StorageKey::StorageAccounts),
+            next_order_nonce: 0,
+            orders_by_account: LookupMap::new(StorageKey::OrdersByAccount),
+            trades: LookupMap::new(StorageKey::Trades),
+            candles: LookupMap::new(StorageKey::Candles),
+            owner_id: owner_id.unwrap_or_else(env::current_account_id),
+            pending_owner_id: None,
+            roles: LookupMap::new(StorageKey::Roles),
+            paused: false,
+            halted_pairs: UnorderedSet::new(StorageKey::HaltedPairs),
+            tokens: UnorderedMap::new(StorageKey::Tokens),
+            listing_bond: None,
+            token_metadata: LookupMap::new(StorageKey::TokenMetadata),
+        }
     }
 
     fn match_order(&mut self, sender_id: AccountId, order_id: OrderId, amount: U128, token: AccountId) {
@@ -118,8 +231,10 @@ impl Market {
         }
 
         let order = existed_order.unwrap();
+        require!(order.is_open(), ERR22_ORDER_SETTLING);
 
-        if amount != order.buy_amount {
+        // partial fills are allowed, the taker may send anything up to the remaining buy_amount
+        if amount.0 == 0 || amount.0 > order.buy_amount.0 {
             env::panic_str(ERR05_NOT_VALID_AMOUNT);
         }
 
@@ -127,198 +242,319 @@ impl Market {
             env::panic_str(ERR06_NOT_VALID_TOKEN);
         }
 
+        if order.is_expired() {
+            env::panic_str(ERR15_ORDER_EXPIRED);
+        }
+
+        self.assert_token_listed(&order.sell_token);
+        self.assert_token_listed(&order.buy_token);
+
+        let sell_amount = order.get_sell_amount_for(amount.0);
+        if sell_amount == 0 {
+            env::panic_str(ERR05_NOT_VALID_AMOUNT);
+        }
+
         // todo:  check storage deposit
 
-        let gas_for_next_callback =
-            env::prepaid_gas() - env::used_gas() - FT_TRANSFER_TGAS - RESERVE_TGAS;
+        // an order locked by a chain that runs out of gas would stay `Settling`
+        require!(
+            env::prepaid_gas() - env::used_gas() >= RESERVE_TGAS + SETTLE_CALLBACK_TGAS + MATCH_TGAS,
+            ERR23_NOT_ENOUGH_GAS
+        );
 
-        ft_token::ft_transfer(
-            order.maker,
-            order.buy_amount,
-            "".to_string(),
-            order.buy_token.clone(),
-            ONE_YOCTO,
-            FT_TRANSFER_TGAS,
-        )
-        .then(ext_self::callback_on_send_tokens_to_maker(
-            sender_id, // matcher
-            order.sell_amount,
-            order.sell_token,
-            order.buy_token,
-            order_id,
-            env::current_account_id(),
-            0,
-            gas_for_next_callback,
-        ));
+        self.internal_settle_fills(
+            &sender_id,
+            vec![Fill { order_id, order, sell_amount, buy_amount: amount.0 }],
+        );
     }
 
-    fn get_or_create_fee_info(&mut self, sell_token: &AccountId) -> Fee {
-        match self.fees.get(sell_token) {
-            Some(fee) => fee,
-            None => {
-                let fee = Fee {
-                    // 1 / 100 = 0.01%
-                    // 100% = HUNDRED_PERCENT = 10000
-                    percent: 100,
-                    earned: 0
-                };
+    /// Walks the `key` book from the best price and picks fills paying at most `amount`
+    /// of its buy token. Stops at the first order `accept` rejects or when there is
+    /// no gas left for another fill. Fills settled `on_balances` only need the gas of
+    /// their bookkeeping, the others also the transfers and the settle callback.
+    fn internal_collect_fills<F: Fn(&Order) -> bool>(
+        &self,
+        key: &String,
+        amount: u128,
+        on_balances: bool,
+        accept: F,
+    ) -> Vec<Fill> {
+        let orders_map = match self.orders.get(key) {
+            Some(orders_map) => orders_map,
+            None => return vec![],
+        };
 
-                self.fees.insert(sell_token, &fee);
-                fee
+        let mut fills = vec![];
+        let mut amount_left = amount;
+        let (reserved_gas, fill_gas) = if on_balances {
+            (RESERVE_TGAS, FILL_TGAS)
+        } else {
+            (RESERVE_TGAS + SETTLE_CALLBACK_TGAS, MATCH_TGAS)
+        };
+        let mut gas_left = (env::prepaid_gas() - env::used_gas()).0.saturating_sub(reserved_gas.0);
+        for (order_id, order) in orders_map.iter() {
+            if amount_left == 0 || gas_left < fill_gas.0 || !accept(&order) {
+                break;
+            }
+
+            // expired orders wait for `cleanup_expired`, settling ones for their callbacks
+            if !order.is_open() || order.is_expired() {
+                continue;
             }
+
+            let buy_amount = std::cmp::min(amount_left, order.buy_amount.0);
+            let sell_amount = order.get_sell_amount_for(buy_amount);
+            if sell_amount == 0 {
+                break;
+            }
+
+            amount_left -= buy_amount;
+            gas_left -= fill_gas.0;
+            fills.push(Fill { order_id, order, sell_amount, buy_amount });
         }
-    }
 
-    fn take_fee(
-        &mut self,
-        amount: u128,
-        sell_token: &AccountId
-    ) -> u128 {
-        let fee_value = self.get_or_create_fee_info(sell_token).percent;
-        let fee = amount * ((HUNDRED_PERCENT - fee_value) as u128) / (HUNDRED_PERCENT as u128);
-        fee
+        fills
     }
 
-    pub fn set_fee(&mut self, token: AccountId, percent: u16) {
-        assert_owner();
-        assert!(percent <= HUNDRED_PERCENT);
-        assert!(percent >= 1);
-
-        let earned = match self.fees.get(&token) {
-            Some(v) => v.earned,
-            None => 0
+    /// Locks the orders of the fills as `Settling` and pays their makers in one batch
+    /// of transfers. `callback_on_settle_fills` then fills the orders and pays the taker.
+    /// All fills come from the same book.
+    fn internal_settle_fills(&mut self, taker: &AccountId, fills: Vec<Fill>) {
+        let (sell_token, buy_token) = match fills.first() {
+            Some(fill) => (fill.order.sell_token.clone(), fill.order.buy_token.clone()),
+            None => return,
         };
 
-        self.fees.insert(&token, &Fee::new(percent, earned));
-    }
+        let mut payments: Option<Promise> = None;
+        let mut settled = Vec::with_capacity(fills.len());
+        for fill in fills {
+            self.internal_set_order_status(&fill.order_id, OrderStatus::Settling);
+            let payment = ft_token::ft_transfer(
+                fill.order.maker,
+                U128(fill.buy_amount),
+                "".to_string(),
+                buy_token.clone(),
+                ONE_YOCTO,
+                SETTLE_TRANSFER_TGAS,
+            );
+            payments = Some(match payments {
+                Some(payments) => payments.and(payment),
+                None => payment,
+            });
+            settled.push(FillView {
+                order_id: fill.order_id,
+                sell_amount: U128(fill.sell_amount),
+                buy_amount: U128(fill.buy_amount),
+            });
+        }
 
-    pub fn transfer_earned_fees(
-        &mut self,
-        token: AccountId,
-        amount: u128,
-        receiver: AccountId
-    ) {
-        assert_owner();
+        let callback_gas = Gas(SETTLE_CALLBACK_TGAS.0 + FILL_TGAS.0 * settled.len() as u64);
+        if let Some(payments) = payments {
+            payments.then(ext_self::callback_on_settle_fills(
+                taker.clone(),
+                sell_token,
+                buy_token,
+                settled,
+                env::current_account_id(),
+                0,
+                callback_gas,
+            ));
+        }
+    }
 
-        let fee_info = self.fees.get(&token).expect(ERR10_NOT_ENOUGH);
+    /// Fills the new order against the opposite book for as long as the resting
+    /// orders cross its limit price.
+    /// Orders funded from the internal balance settle their fills between internal
+    /// balances, the others through the transfer callback chain.
+    fn internal_match_new_order(&mut self, new_order: &mut Order, from_balance: bool) {
+        let key = compose_key(&new_order.buy_token, &new_order.sell_token);
+        let limit = new_order.clone();
+        let fills = self.internal_collect_fills(&key, new_order.sell_amount.0, from_balance, |order| {
+            limit.crosses(order)
+        });
 
-        if fee_info.earned == 0 {
-            env::panic_str("no need to transfer zero amount");
+        for fill in fills.iter() {
+            new_order.take(fill.buy_amount, fill.sell_amount);
         }
 
-        if amount > fee_info.earned {
-            env::panic_str(ERR10_NOT_ENOUGH);
+        if from_balance {
+            for fill in fills {
+                self.internal_settle_fill_on_balances(&limit.maker, fill);
+            }
+        } else {
+            self.internal_settle_fills(&limit.maker, fills);
         }
+    }
 
-        let gas_for_next_callback =
-            env::prepaid_gas() - env::used_gas() - FT_TRANSFER_TGAS - RESERVE_TGAS;
+    /// Settles a fill right away: the maker gets `buy_amount` and the taker gets
+    /// `sell_amount` minus fee on their internal balances.
+    fn internal_settle_fill_on_balances(&mut self, taker: &AccountId, fill: Fill) {
+        let order = fill.order;
+        let split = self.internal_split_fee(fill.sell_amount, &order.sell_token);
 
-        ft_token::ft_transfer(
-            receiver.clone(),
-            U128(amount),
-            "transfer from contract".to_string(),
-            token.clone(),
-            ONE_YOCTO,
-            FT_TRANSFER_TGAS
-        ).then(ext_self::callback_on_send_tokens_to_ext_account(
-            token,
-            receiver,
-            U128(amount),
-            env::current_account_id(),
-            0,
-            gas_for_next_callback
-        ));
+        self.internal_deposit(&order.maker, &order.buy_token, fill.buy_amount);
+        self.internal_deposit(taker, &order.sell_token, split.net.0);
+        self.internal_record_fee(&fill.order_id, &order.sell_token, split);
+
+        let key = compose_key(&order.sell_token, &order.buy_token);
+        let orders_map = self
+            .orders
+            .get(&key)
+            .unwrap_or_else(|| env::panic_str(ERR01_INTERNAL));
+        self.internal_fill_order(&key, orders_map, fill.order_id, taker, fill.sell_amount, fill.buy_amount);
     }
 
-    #[private]
-    fn callback_on_send_tokens_to_ext_account(
-        &mut self, token: AccountId, receiver: AccountId, amount: U128
-    ) {
-        assert_eq!(
-            env::promise_results_count(),
-            1,
-            "{}",
-            ERR08_NOT_CORRECT_PROMISE_RESULT_COUNT
-        );
+    /// Spends the deposited `amount` of `token` on the best `buy_token` offers.
+    /// Returns the unspent part of the deposit.
+    fn market_buy(
+        &mut self,
+        sender_id: AccountId,
+        token: AccountId,
+        amount: U128,
+        buy_token: AccountId,
+        min_receive: U128,
+    ) -> U128 {
+        if token == buy_token {
+            env::panic_str(ERR06_NOT_VALID_TOKEN);
+        }
+        self.assert_token_listed(&token);
+        self.assert_token_listed(&buy_token);
 
-        match env::promise_result(0) {
-            PromiseResult::Failed => {
-                env::log_str("failed to transfer tokens to receiver")
-            },
-            PromiseResult::Successful(_) => {
-                env::log_str("tokens successfully transferred to receiver");
+        let key = compose_key(&buy_token, &token);
+        let fills = self.internal_collect_fills(&key, amount.0, false, |_| true);
 
-                let mut fee_info = match self.fees.get(&token) {
-                    Some(v) => v,
-                    None => env::panic_str("failed to get fee info for token")
-                };
+        let mut spent = 0;
+        let mut received = 0;
+        for fill in fills.iter() {
+            spent += fill.buy_amount;
+            received += self.internal_split_fee(fill.sell_amount, &buy_token).net.0;
+        }
 
-                fee_info.earned = fee_info.earned.saturating_sub(amount.0);
-                self.fees.insert(&token, &fee_info);
-            }
-            _ => unreachable!()
+        if received < min_receive.0 {
+            env::panic_str(ERR12_SLIPPAGE);
         }
+
+        self.internal_settle_fills(&sender_id, fills);
+
+        U128(amount.0 - spent)
     }
-    
+
+    /// Finishes the fills once their makers were paid, `fills` come in the order of
+    /// the payments. Paid fills are filled and the taker gets their sell amounts minus
+    /// fee in one transfer. The orders of the failed ones are open again and the taker
+    /// gets their buy amounts back.
     #[private]
-    pub fn callback_on_send_tokens_to_maker(
+    pub fn callback_on_settle_fills(
         &mut self,
-        sender_id: AccountId,
-        sell_amount: U128,
+        taker: AccountId,
         sell_token: AccountId,
         buy_token: AccountId,
-        order_id: OrderId,
+        fills: Vec<FillView>,
     ) {
         assert_eq!(
             env::promise_results_count(),
-            1,
+            fills.len() as u64,
             "{}",
             ERR08_NOT_CORRECT_PROMISE_RESULT_COUNT
         );
-        let is_promise_success: bool = match env::promise_result(0) {
-            PromiseResult::NotReady => unreachable!(),
-            PromiseResult::Successful(_) => true,
-            PromiseResult::Failed => false,
-        };
-
-        if is_promise_success {
-            let gas_for_next_callback =
-                env::prepaid_gas() - env::used_gas() - FT_TRANSFER_TGAS - RESERVE_TGAS;
 
-            let fee = self.take_fee(sell_amount.0, &sell_token);
+        let key = compose_key(&sell_token, &buy_token);
+        let mut payouts = vec![];
+        let mut payout = 0;
+        let mut refund = 0;
+        for (index, fill) in fills.into_iter().enumerate() {
+            match env::promise_result(index as u64) {
+                PromiseResult::NotReady => unreachable!(),
+                PromiseResult::Successful(_) => {
+                    let split = self.internal_split_fee(fill.sell_amount.0, &sell_token);
+                    payout += split.net.0;
+                    payouts.push((fill.order_id, split.net));
+                    self.internal_record_fee(&fill.order_id, &sell_token, split);
+
+                    let orders_map = self
+                        .orders
+                        .get(&key)
+                        .unwrap_or_else(|| env::panic_str(ERR01_INTERNAL));
+                    self.internal_fill_order(
+                        &key,
+                        orders_map,
+                        fill.order_id,
+                        &taker,
+                        fill.sell_amount.0,
+                        fill.buy_amount.0,
+                    );
+                }
+                PromiseResult::Failed => {
+                    // for example maker did not registred buy_token
+                    self.internal_set_order_status(&fill.order_id, OrderStatus::Open);
+                    let maker = self.order_id_to_order.get(&fill.order_id).map(|order| order.maker);
+                    emit_settlement_failed(&fill.order_id, maker.as_ref(), &taker, &buy_token, fill.buy_amount.0);
+                    refund += fill.buy_amount.0;
+                }
+            }
+        }
 
-            // check storage deposit
+        if payout > 0 {
             ft_token::ft_transfer(
-                sender_id,
-                U128(fee),
+                taker.clone(),
+                U128(payout),
                 "".to_string(),
                 sell_token.clone(),
                 ONE_YOCTO,
-                FT_TRANSFER_TGAS,
-            ).then(ext_self::callback_after_deposit(
-                U128(fee),
+                SETTLE_TRANSFER_TGAS,
+            ).then(ext_self::callback_on_taker_payout(
+                taker.clone(),
                 sell_token,
+                payouts,
+                env::current_account_id(),
+                0,
+                RESERVE_TGAS
+            ));
+        }
+        if refund > 0 {
+            ft_token::ft_transfer(
+                taker.clone(),
+                U128(refund),
+                "refund".to_string(),
+                buy_token.clone(),
+                ONE_YOCTO,
+                SETTLE_TRANSFER_TGAS,
+            ).then(ext_self::callback_on_refund(
+                taker,
                 buy_token,
-                order_id,
+                U128(refund),
                 env::current_account_id(),
                 0,
-                gas_for_next_callback
+                RESERVE_TGAS
             ));
+        }
+    }
 
-        } else {
-            // for example maker did not registred buy_token
-            env::panic_str(ERR01_INTERNAL);
+    /// Credits the payout of the fills to the internal balance of the taker if the
+    /// transfer failed. The fills stand, their makers are already paid.
+    #[private]
+    pub fn callback_on_taker_payout(&mut self, taker: AccountId, token: AccountId, payouts: Vec<(OrderId, U128)>) {
+        assert_eq!(
+            env::promise_results_count(),
+            1,
+            "{}",
+            ERR08_NOT_CORRECT_PROMISE_RESULT_COUNT
+        );
+
+        if let PromiseResult::Failed = env::promise_result(0) {
+            env::log_str("failed to transfer token to sender");
+            for (order_id, amount) in payouts {
+                let maker = self.order_id_to_order.get(&order_id).map(|order| order.maker);
+                emit_settlement_failed(&order_id, maker.as_ref(), &taker, &token, amount.0);
+                self.internal_deposit(&taker, &token, amount.0);
+            }
         }
     }
 
+    /// Credits the amount to the internal balance of the receiver if the transfer
+    /// (a refund or a withdrawal) failed.
     #[private]
-    pub fn callback_after_deposit(
-        &mut self,
-        fee: U128,
-        sell_token: AccountId,
-        buy_token: AccountId,
-        order_id: OrderId
-    ) {
+    pub fn callback_on_refund(&mut self, receiver: AccountId, token: AccountId, amount: U128) {
         assert_eq!(
             env::promise_results_count(),
             1,
@@ -327,42 +563,142 @@ impl Market {
         );
 
         if let PromiseResult::Failed = env::promise_result(0) {
-            env::log_str("failed to transfer token to sender")
-        } else {
-            env::log_str("transfer token to sender completed successfully");
-            let mut fee_info = self.get_or_create_fee_info(&sell_token);
-            fee_info.earned += fee.0;
+            env::log_str(&format!("failed to transfer tokens to {}, credited to internal balance", receiver));
+            self.internal_deposit(&receiver, &token, amount.0);
+        }
+    }
+
+    /// Transfers `amount` of `token` from the internal balance of the caller.
+    #[payable]
+    pub fn withdraw(&mut self, token: AccountId, amount: U128) {
+        assert_one_yocto();
+        let account_id = env::predecessor_account_id();
+        self.internal_withdraw(&account_id, &token, amount.0);
+
+        ft_token::ft_transfer(
+            account_id.clone(),
+            amount,
+            "withdraw".to_string(),
+            token.clone(),
+            ONE_YOCTO,
+            FT_TRANSFER_TGAS,
+        ).then(ext_self::callback_on_refund(
+            account_id,
+            token,
+            amount,
+            env::current_account_id(),
+            0,
+            RESERVE_TGAS
+        ));
+    }
 
-            self.fees.insert(&sell_token, &fee_info);
+    /// Places the order funded by `amount` of `token` the maker has just transferred.
+    /// Returns the part of the deposit above `sell_amount`, which is refunded to the maker.
+    fn add_order(
+        &mut self,
+        action: NewOrderAction,
+        sender: AccountId,
+        token: AccountId,
+        amount: U128,
+    ) -> U128 {
+        if action.sell_token != token || action.sell_amount.0 > amount.0 {
+            env::panic_str(ERR11_DEPOSIT_MISMATCH);
         }
 
-        let key = compose_key(&sell_token, &buy_token);
-        let orders_map = self
-            .orders
-            .get(&key)
-            .unwrap_or_else(|| env::panic_str(ERR01_INTERNAL));
-        self.internal_remove_order(&key, orders_map, order_id);
+        if action.sell_amount.0 == 0 || action.buy_amount.0 == 0 {
+            env::panic_str(ERR05_NOT_VALID_AMOUNT);
+        }
+
+        let excess = amount.0 - action.sell_amount.0;
+        let unplaced = self.internal_place_order(Order::from_action(action, sender), false);
+
+        U128(excess + unplaced)
     }
 
-    fn add_order(&mut self, action: NewOrderAction, sender: AccountId) {
-        let new_order = Order::from_action(action, sender);
+    /// Places the order funded from the internal balance of the caller.
+    /// Whatever can't be placed goes back to the balance.
+    #[payable]
+    pub fn place_order(&mut self, action: NewOrderAction) {
+        assert_one_yocto();
+        if self.internal_is_halted(&action.sell_token, &action.buy_token) {
+            env::panic_str(ERR16_TRADING_HALTED);
+        }
+        if action.sell_amount.0 == 0 || action.buy_amount.0 == 0 {
+            env::panic_str(ERR05_NOT_VALID_AMOUNT);
+        }
+
+        let maker = env::predecessor_account_id();
+        self.internal_withdraw(&maker, &action.sell_token, action.sell_amount.0);
+
+        let sell_token = action.sell_token.clone();
+        let unplaced = self.internal_place_order(Order::from_action(action, maker.clone()), true);
+        if unplaced > 0 {
+            self.internal_deposit(&maker, &sell_token, unplaced);
+        }
+    }
+
+    /// Matches the new order against the book and rests what is left of it.
+    /// Returns the part of `sell_amount` that was neither traded nor placed.
+    fn internal_place_order(&mut self, mut new_order: Order, from_balance: bool) -> u128 {
+        if new_order.is_expired() {
+            env::panic_str(ERR15_ORDER_EXPIRED);
+        }
+        self.assert_token_listed(&new_order.sell_token);
+        self.assert_token_listed(&new_order.buy_token);
+
+        self.internal_match_new_order(&mut new_order, from_balance);
+        if new_order.is_filled() {
+            // whatever is left can't buy anything at the order's price
+            return new_order.sell_amount.0;
+        }
+        if self.internal_crosses_book(&new_order) {
+            // matching ran out of gas, a resting remainder would cross the book
+            return new_order.sell_amount.0;
+        }
+
+        let storage_before = env::storage_usage();
 
         let key = compose_key(&new_order.sell_token, &new_order.buy_token);
         let mut orders_map = self.orders.get(&key)
             .unwrap_or(TreeMap::new(key.as_bytes()));
 
-        let order_id = new_order.get_id();
+        let order_id = self.internal_next_order_id(&new_order);
         if orders_map.contains_key(&order_id) {
             env::panic_str(ERR02_ORDER_ALREADY_EXISTS);
         }
 
-        orders_map.insert(&OrderId::from_order(&new_order), &new_order);
+        orders_map.insert(&order_id, &new_order);
 
         self.order_id_to_order.insert(&order_id, &new_order);
         self.orders.insert(&key, &orders_map);
+        self.internal_add_account_order(&new_order.maker, &order_id);
+
+        // the byte count has a fixed size, writing it back doesn't change the usage
+        new_order.storage_bytes = env::storage_usage() - storage_before;
+        orders_map.insert(&order_id, &new_order);
+        self.order_id_to_order.insert(&order_id, &new_order);
+        self.internal_charge_storage(&new_order.maker, new_order.storage_bytes);
+        emit_order_created(&order_id, &new_order);
+
+        0
     }
 
+    /// Whether the best open order of the opposite book crosses the limit price of `order`.
+    fn internal_crosses_book(&self, order: &Order) -> bool {
+        let key = compose_key(&order.buy_token, &order.sell_token);
+        let orders_map = match self.orders.get(&key) {
+            Some(orders_map) => orders_map,
+            None => return false,
+        };
+        let best = orders_map.iter().find(|(_, other)| other.is_open() && !other.is_expired());
+        best.is_some_and(|(_, other)| order.crosses(&other))
+    }
+
+    /// Cancels the order of the caller and refunds what is left of it. A failed
+    /// refund is credited to the internal balance of the maker by `callback_on_cancel_refund`.
+    #[payable]
     pub fn remove_order(&mut self, sell_token: AccountId, buy_token: AccountId, order_id: OrderId) {
+        assert_one_yocto();
         let key = compose_key(&sell_token, &buy_token);
         let order_by_key = self.orders.get(&key);
 
@@ -377,142 +713,579 @@ impl Market {
             env::panic_str(ERR03_ORDER_NOT_FOUND);
         }
 
-        let order = order.unwrap();
-        let maker = order.maker;
+        let mut order = order.unwrap();
+        let maker = order.maker.clone();
         if maker != env::predecessor_account_id() {
             env::panic_str(ERR04_PERMISSION_DENIED)
         }
+        require!(order.is_open(), ERR22_ORDER_SETTLING);
 
         self.internal_remove_order(&key, orders_map, order_id);
+        order.status = OrderStatus::Cancelled;
+        emit_order_cancelled(&order_id, &order);
 
         ft_token::ft_transfer(
-            maker,
+            maker.clone(),
             order.sell_amount,
-            "".to_string(),
+            "cancel".to_string(),
             order.sell_token.clone(),
             ONE_YOCTO,
             FT_TRANSFER_TGAS,
-        );
+        ).then(ext_self::callback_on_cancel_refund(
+            order_id,
+            maker,
+            order.sell_token,
+            order.sell_amount,
+            env::current_account_id(),
+            0,
+            RESERVE_TGAS
+        ));
     }
 
-    fn internal_remove_order(
-        &mut self,
-        key: &String,
-        mut orders_map: TreeMap<OrderId, Order>,
-        order_id: OrderId,
-    ) {
-        orders_map.remove(&order_id);
-
-        if orders_map.len() == 0 {
-            self.orders.remove(key);
-        } else {
-            self.orders.insert(key, &orders_map);
+    /// Credits the refund of a cancelled order to the internal balance of the maker
+    /// if the transfer failed, for example the maker unregistered from the token.
+    /// The order itself stays cancelled, its storage is already released.
+    #[private]
+    pub fn callback_on_cancel_refund(&mut self, order_id: OrderId, maker: AccountId, token: AccountId, amount: U128) {
+        self.callback_on_refund(maker, token, amount);
+        if let PromiseResult::Failed = env::promise_result(0) {
+            env::log_str(&format!("failed to refund cancelled order {}", order_id));
         }
+    }
 
-        self.order_id_to_order.remove(&order_id);
+    /// Reopens an order left `Settling` by a settlement chain that died midway.
+    /// Owner only, the payments of that fill are sorted out with the parties by hand.
+    #[payable]
+    pub fn reopen_order(&mut self, order_id: OrderId) {
+        assert_one_yocto();
+        self.assert_owner();
+        let order = self
+            .order_id_to_order
+            .get(&order_id)
+            .unwrap_or_else(|| env::panic_str(ERR03_ORDER_NOT_FOUND));
+        require!(order.status == OrderStatus::Settling, ERR24_ORDER_NOT_SETTLING);
+
+        self.internal_set_order_status(&order_id, OrderStatus::Open);
+        env::log_str(&format!("order {} of {} is open again", order_id, order.maker));
     }
 
-    pub fn get_order(&self, order_id: OrderId) -> Option<Order> {
-        self.order_id_to_order.get(&order_id)
+    fn internal_next_order_id(&mut self, order: &Order) -> OrderId {
+        let order_id = OrderId::new(order, self.next_order_nonce);
+        self.next_order_nonce += 1;
+        order_id
     }
 
-    pub fn get_orders(
-        &self,
-        sell_token: AccountId,
-        buy_token: AccountId,
-    ) -> Option<Vec<OrderView>> {
-        let key = compose_key(&sell_token, &buy_token);
-        let order_by_key = self.orders.get(&key);
+    /// Reads up to `limit` orders of the `pair` ("sell#buy" as returned by `get_pairs`)
+    /// after `from_index`, or from the best price, and removes the expired ones.
+    /// Makers get the remaining `sell_amount` back on their internal balance, the caller
+    /// is rewarded with the storage deposit the removed orders held.
+    pub fn cleanup_expired(&mut self, pair: String, from_index: Option<OrderId>, limit: Option<u64>) -> CleanupResult {
+        let limit = limit.unwrap_or(DEFAULT_CLEANUP_LIMIT) as usize;
+        let mut scanned: Vec<(OrderId, Order)> = match self.orders.get(&pair) {
+            Some(orders_map) => {
+                let order_iter: Box<dyn Iterator<Item = (OrderId, Order)>> = match from_index {
+                    Some(from_index) => Box::new(orders_map.iter_from(from_index)),
+                    None => Box::new(orders_map.iter()),
+                };
+                // one more to know whether the book goes on
+                order_iter.take(limit + 1).collect()
+            }
+            None => return CleanupResult { removed: 0, next_index: None },
+        };
+        let next_index = if scanned.len() > limit {
+            scanned.truncate(limit);
+            scanned.last().map(|(order_id, _)| *order_id)
+        } else {
+            None
+        };
+        let expired: Vec<(OrderId, Order)> = scanned
+            .into_iter()
+            .filter(|(_, order)| order.is_open() && order.is_expired())
+            .collect();
 
-        if order_by_key.is_none() {
-            return None;
-        }
+        let mut reward = 0;
+        for (order_id, order) in expired.iter() {
+            let orders_map = self.orders.get(&pair).unwrap_or_else(|| env::panic_str(ERR01_INTERNAL));
 
-        let mut res = vec![];
+            self.internal_remove_order(&pair, orders_map, *order_id);
+            reward += self.internal_take_storage_reward(&order.maker, order.storage_bytes);
 
-        let orders = order_by_key.unwrap();
-        // println!("orders.contains_key: {}", orders.contains_key(&6459152053938679878));
-        let order_iter = orders.iter().take(5);
-        for order in order_iter {
-            res.push(OrderView {
-                order: order.1.clone(),
-                order_id: order.0.clone(),
-            })
+            self.internal_deposit(&order.maker, &order.sell_token, order.sell_amount.0);
+            let order = Order { status: OrderStatus::Cancelled, ..order.clone() };
+            emit_order_cancelled(order_id, &order);
         }
 
-        return Some(res);
+        if reward > 0 {
+            Promise::new(env::predecessor_account_id()).transfer(reward);
+        }
+
+        CleanupResult { removed: expired.len() as u64, next_index }
     }
 
-    pub fn get_pairs(&self) -> Vec<String> {
-        let keys = self.orders.keys_as_vector();
-        keys.to_vec()
+    /// Cancels the given orders of the caller. Ids of orders that no longer exist
+    /// (filled or cancelled already) and of orders in settlement are skipped.
+    #[payable]
+    pub fn cancel_orders(&mut self, order_ids: Vec<OrderId>) -> CancelOrdersResult {
+        assert_one_yocto();
+        self.internal_cancel_orders(&env::predecessor_account_id(), order_ids)
     }
-}
 
-fn compose_key(sell_token: &AccountId, buy_token: &AccountId) -> String {
-    let mut key = String::from(sell_token.as_str());
-    key.push_str("#");
-    key.push_str(buy_token.as_str());
-    key
-}
+    /// Cancels all orders of the caller, or only those in the `pair` ("sell#buy").
+    #[payable]
+    pub fn cancel_all_orders(&mut self, pair: Option<String>) -> CancelOrdersResult {
+        assert_one_yocto();
+        let maker = env::predecessor_account_id();
+        let order_ids = match self.orders_by_account.get(&maker) {
+            Some(account_orders) => account_orders
+                .iter()
+                .filter(|order_id| match &pair {
+                    Some(pair) => self
+                        .order_id_to_order
+                        .get(order_id)
+                        .is_some_and(|order| &compose_key(&order.sell_token, &order.buy_token) == pair),
+                    None => true,
+                })
+                .collect(),
+            None => vec![],
+        };
 
-#[cfg(test)]
-mod tests {
-    use near_sdk::{test_utils::VMContextBuilder, testing_env};
+        self.internal_cancel_orders(&maker, order_ids)
+    }
 
-    use super::*;
+    /// Removes the orders while the gas is enough for them and the refunds,
+    /// then refunds every sell token with a single transfer.
+    fn internal_cancel_orders(&mut self, maker: &AccountId, order_ids: Vec<OrderId>) -> CancelOrdersResult {
+        let mut refunds: Vec<(AccountId, u128)> = vec![];
+        let mut cancelled = vec![];
+        let mut remaining = 0;
+
+        for (index, order_id) in order_ids.iter().enumerate() {
+            let mut order = match self.order_id_to_order.get(order_id) {
+                Some(order) => order,
+                None => continue,
+            };
+            if &order.maker != maker {
+                env::panic_str(ERR04_PERMISSION_DENIED);
+            }
+            if !order.is_open() {
+                continue;
+            }
 
-    fn create_test_order(sell_amount: u128, buy_amount: u128) -> Order {
-        Order {
-            maker: AccountId::new_unchecked(String::from("maker.near")),
-            sell_token: AccountId::new_unchecked(String::from("xabr.allbridge.testnet")),
-            sell_amount: U128(sell_amount),
-            buy_token: AccountId::new_unchecked(String::from("abr.allbridge.testnet")),
-            buy_amount: U128(buy_amount),
-        }
-    }
+            let refund = refunds.iter().position(|(token, _)| token == &order.sell_token);
+            let transfers = refunds.len() + refund.is_none() as usize;
+            let gas_needed = REFUND_TGAS.0 * transfers as u64 + CANCEL_ORDER_TGAS.0;
+            if env::prepaid_gas().0 < env::used_gas().0 + gas_needed {
+                remaining = (order_ids.len() - index) as u64;
+                break;
+            }
 
-    fn fee_test(contract: &mut Market, token: &str, amount: u128, expect: u128) {
-        assert_eq!(
-            contract.take_fee(amount, &(token.parse().unwrap())),
-            expect
-        );
-    }
+            let key = compose_key(&order.sell_token, &order.buy_token);
+            let orders_map = self.orders.get(&key).unwrap_or_else(|| env::panic_str(ERR01_INTERNAL));
+            self.internal_remove_order(&key, orders_map, *order_id);
+            order.status = OrderStatus::Cancelled;
+            emit_order_cancelled(order_id, &order);
 
-    #[test]
-    #[should_panic]
-    fn test_fee_overflow() {
-        let mut contract = Market {
-            orders: UnorderedMap::new(StorageKey::Orders),
-            order_id_to_order: LookupMap::new(StorageKey::OrderIdToOrder),
-            version: 1,
-            fees: LookupMap::new(StorageKey::FeesByAccountIds)
+            match refund {
+                Some(index) => refunds[index].1 += order.sell_amount.0,
+                None => refunds.push((order.sell_token.clone(), order.sell_amount.0)),
+            }
+            cancelled.push(*order_id);
+        }
+
+        for (token, amount) in refunds {
+            ft_token::ft_transfer(
+                maker.clone(),
+                U128(amount),
+                "cancel".to_string(),
+                token.clone(),
+                ONE_YOCTO,
+                FT_TRANSFER_TGAS,
+            ).then(ext_self::callback_on_refund(
+                maker.clone(),
+                token,
+                U128(amount),
+                env::current_account_id(),
+                0,
+                RESERVE_TGAS
+            ));
+        }
+
+        CancelOrdersResult { cancelled, remaining }
+    }
+
+    /// Reduces the remaining amounts of the order by the filled part.
+    /// A fully filled order is removed, a partially filled one is open again
+    /// under the same `OrderId`.
+    fn internal_fill_order(
+        &mut self,
+        key: &String,
+        mut orders_map: TreeMap<OrderId, Order>,
+        order_id: OrderId,
+        taker: &AccountId,
+        sell_amount: u128,
+        buy_amount: u128,
+    ) {
+        let mut order = orders_map
+            .get(&order_id)
+            .unwrap_or_else(|| env::panic_str(ERR03_ORDER_NOT_FOUND));
+
+        order.fill(sell_amount, buy_amount);
+        order.status = if order.is_filled() { OrderStatus::Filled } else { OrderStatus::Open };
+        emit_order_filled(&order_id, &order, taker, sell_amount, buy_amount);
+        self.internal_record_fill(order_id, &order, taker, sell_amount, buy_amount);
+
+        if order.is_filled() {
+            self.internal_remove_order(key, orders_map, order_id);
+        } else {
+            orders_map.insert(&order_id, &order);
+            self.orders.insert(key, &orders_map);
+            self.order_id_to_order.insert(&order_id, &order);
+        }
+    }
+
+    fn internal_remove_order(
+        &mut self,
+        key: &String,
+        mut orders_map: TreeMap<OrderId, Order>,
+        order_id: OrderId,
+    ) {
+        let order = orders_map.remove(&order_id);
+
+        if orders_map.is_empty() {
+            self.orders.remove(key);
+        } else {
+            self.orders.insert(key, &orders_map);
+        }
+
+        self.order_id_to_order.remove(&order_id);
+
+        if let Some(order) = order {
+            self.internal_remove_account_order(&order.maker, &order_id);
+            self.internal_release_storage(&order.maker, order.storage_bytes);
+        }
+    }
+
+    /// Updates the status of the order in its book and in the id lookup.
+    /// Does nothing if the order is gone.
+    fn internal_set_order_status(&mut self, order_id: &OrderId, status: OrderStatus) {
+        let mut order = match self.order_id_to_order.get(order_id) {
+            Some(order) => order,
+            None => return,
+        };
+        order.status = status;
+
+        let key = compose_key(&order.sell_token, &order.buy_token);
+        let mut orders_map = self.orders.get(&key).unwrap_or_else(|| env::panic_str(ERR01_INTERNAL));
+        orders_map.insert(order_id, &order);
+        self.orders.insert(&key, &orders_map);
+        self.order_id_to_order.insert(order_id, &order);
+    }
+
+    fn internal_add_account_order(&mut self, account_id: &AccountId, order_id: &OrderId) {
+        let mut account_orders = self.orders_by_account.get(account_id).unwrap_or_else(|| {
+            UnorderedSet::new(StorageKey::AccountOrders { account_id: account_id.clone() })
+        });
+        account_orders.insert(order_id);
+        self.orders_by_account.insert(account_id, &account_orders);
+    }
+
+    fn internal_remove_account_order(&mut self, account_id: &AccountId, order_id: &OrderId) {
+        if let Some(mut account_orders) = self.orders_by_account.get(account_id) {
+            account_orders.remove(order_id);
+            if account_orders.is_empty() {
+                self.orders_by_account.remove(account_id);
+            } else {
+                self.orders_by_account.insert(account_id, &account_orders);
+            }
+        }
+    }
+
+    fn internal_deposit(&mut self, account_id: &AccountId, token: &AccountId, amount: u128) {
+        let mut balances = self.balances.get(account_id).unwrap_or_else(|| {
+            UnorderedMap::new(StorageKey::AccountBalances { account_id: account_id.clone() })
+        });
+        let balance = balances.get(token).unwrap_or(0);
+
+        balances.insert(token, &(balance + amount));
+        self.balances.insert(account_id, &balances);
+    }
+
+    fn internal_withdraw(&mut self, account_id: &AccountId, token: &AccountId, amount: u128) {
+        let mut balances = self
+            .balances
+            .get(account_id)
+            .unwrap_or_else(|| env::panic_str(ERR10_NOT_ENOUGH));
+        let balance = balances.get(token).unwrap_or(0);
+
+        if amount == 0 || amount > balance {
+            env::panic_str(ERR10_NOT_ENOUGH);
+        }
+
+        if balance == amount {
+            balances.remove(token);
+        } else {
+            balances.insert(token, &(balance - amount));
+        }
+
+        if balances.is_empty() {
+            self.balances.remove(account_id);
+        } else {
+            self.balances.insert(account_id, &balances);
+        }
+    }
+
+    pub fn get_balances(&self, account_id: AccountId) -> Vec<(AccountId, U128)> {
+        match self.balances.get(&account_id) {
+            Some(balances) => balances
+                .iter()
+                .map(|(token, balance)| (token, U128(balance)))
+                .collect(),
+            None => vec![],
+        }
+    }
+
+    pub fn get_balance(&self, account_id: AccountId, token: AccountId) -> U128 {
+        let balance = self
+            .balances
+            .get(&account_id)
+            .and_then(|balances| balances.get(&token))
+            .unwrap_or(0);
+        U128(balance)
+    }
+
+    pub fn get_order(&self, order_id: OrderId) -> Option<Order> {
+        self.order_id_to_order.get(&order_id)
+    }
+
+    /// Orders of the pair, best price first. Pass the `order_id` of the last
+    /// order of the previous page as `from_index` to get the next one.
+    pub fn get_orders(
+        &self,
+        sell_token: AccountId,
+        buy_token: AccountId,
+        from_index: Option<OrderId>,
+        limit: Option<u64>,
+    ) -> Option<Vec<OrderView>> {
+        let key = compose_key(&sell_token, &buy_token);
+        let orders = self.orders.get(&key)?;
+        let limit = limit.unwrap_or(DEFAULT_ORDERS_LIMIT) as usize;
+
+        let order_iter: Box<dyn Iterator<Item = (OrderId, Order)>> = match from_index {
+            Some(from_index) => Box::new(orders.iter_from(from_index)),
+            None => Box::new(orders.iter()),
+        };
+
+        Some(
+            order_iter
+                .take(limit)
+                .map(|(order_id, order)| OrderView { order, order_id })
+                .collect(),
+        )
+    }
+
+    /// Open orders of the account over all pairs, in no particular order.
+    pub fn get_orders_by_account(
+        &self,
+        account_id: AccountId,
+        from_index: Option<u64>,
+        limit: Option<u64>,
+    ) -> Vec<OrderView> {
+        let account_orders = match self.orders_by_account.get(&account_id) {
+            Some(account_orders) => account_orders,
+            None => return vec![],
+        };
+
+        let order_ids = account_orders.as_vector();
+        let from_index = from_index.unwrap_or(0);
+        let limit = limit.unwrap_or(DEFAULT_ORDERS_LIMIT);
+
+        (from_index..std::cmp::min(from_index.saturating_add(limit), order_ids.len()))
+            .filter_map(|index| order_ids.get(index))
+            .filter_map(|order_id| {
+                self.order_id_to_order
+                    .get(&order_id)
+                    .map(|order| OrderView { order, order_id })
+            })
+            .collect()
+    }
+
+    pub fn get_orders_count(&self, sell_token: AccountId, buy_token: AccountId) -> u64 {
+        self.orders
+            .get(&compose_key(&sell_token, &buy_token))
+            .map(|orders| orders.len())
+            .unwrap_or(0)
+    }
+
+    /// Both sides of the `base`/`quote` market aggregated into up to `depth` price levels each.
+    /// Prices are raw amounts of the quote token per raw amount of the base token.
+    pub fn get_order_book(&self, base: AccountId, quote: AccountId, depth: Option<u64>) -> OrderBookView {
+        let depth = depth.unwrap_or(DEFAULT_BOOK_DEPTH) as usize;
+        let asks_key = compose_key(&base, &quote);
+        let bids_key = compose_key(&quote, &base);
+
+        // asks sell the base token, the price is what they want per unit of it
+        let asks = self.internal_price_levels(
+            &asks_key,
+            depth,
+            |price| (price.buy_amount.0, price.sell_amount.0),
+            |order| order.sell_amount.0,
+        );
+        // bids sell the quote token, the price is what they give per unit of the base one
+        let bids = self.internal_price_levels(
+            &bids_key,
+            depth,
+            |price| (price.sell_amount.0, price.buy_amount.0),
+            |order| order.buy_amount.0,
+        );
+
+        let best_ask = self.orders.get(&asks_key).and_then(|orders| orders.min()).map(|order_id| order_id.0);
+        let best_bid = self.orders.get(&bids_key).and_then(|orders| orders.min()).map(|order_id| order_id.0);
+        let spread = match (&best_ask, &best_bid) {
+            (Some(ask), Some(bid)) => {
+                let ask_num = U256::from(ask.buy_amount.0) * U256::from(bid.buy_amount.0);
+                let bid_num = U256::from(bid.sell_amount.0) * U256::from(ask.sell_amount.0);
+                let den = U256::from(ask.sell_amount.0) * U256::from(bid.buy_amount.0);
+                Some(if ask_num >= bid_num {
+                    format_decimal(ask_num - bid_num, den, PRICE_DECIMALS)
+                } else {
+                    format!("-{}", format_decimal(bid_num - ask_num, den, PRICE_DECIMALS))
+                })
+            }
+            _ => None,
+        };
+
+        OrderBookView {
+            best_bid: bids.first().map(|level| level.price.clone()),
+            best_ask: asks.first().map(|level| level.price.clone()),
+            bids,
+            asks,
+            spread,
+        }
+    }
+
+    pub fn get_pairs(&self) -> Vec<String> {
+        let keys = self.orders.keys_as_vector();
+        keys.to_vec()
+    }
+}
+
+impl Market {
+    fn internal_price_levels(
+        &self,
+        key: &String,
+        depth: usize,
+        price_of: fn(&Price) -> (u128, u128),
+        quantity_of: fn(&Order) -> u128,
+    ) -> Vec<PriceLevel> {
+        let mut levels: Vec<PriceLevel> = vec![];
+        let orders = match self.orders.get(key) {
+            Some(orders) => orders,
+            None => return levels,
         };
 
+        for (order_id, order) in orders.iter() {
+            let (num, den) = price_of(&order_id.0);
+            let price = format_decimal(U256::from(num), U256::from(den), PRICE_DECIMALS);
+            match levels.last_mut() {
+                Some(level) if level.price == price => {
+                    level.quantity = U128(level.quantity.0 + quantity_of(&order));
+                    level.orders += 1;
+                }
+                _ => {
+                    if levels.len() == depth {
+                        break;
+                    }
+                    levels.push(PriceLevel { price, quantity: U128(quantity_of(&order)), orders: 1 });
+                }
+            }
+        }
+
+        levels
+    }
+}
+
+fn compose_key(sell_token: &AccountId, buy_token: &AccountId) -> String {
+    let mut key = String::from(sell_token.as_str());
+    key.push('#');
+    key.push_str(buy_token.as_str());
+    key
+}
+
+#[cfg(test)]
+mod tests {
+    use near_sdk::{test_utils::{get_created_receipts, get_logs, VMContextBuilder}, testing_env};
+
+    use near_contract_standards::storage_management::StorageManagement;
+    use crate::tokens::TokenStatus;
+    use crate::fees::FeeInfo;
+    use crate::history::{Side, Trade};
+    use crate::fees::FeeSplit;
+    use near_contract_standards::fungible_token::metadata::FungibleTokenMetadata;
+
+    use super::*;
+
+    fn create_test_order(sell_amount: u128, buy_amount: u128) -> Order {
+        Order {
+            maker: AccountId::new_unchecked(String::from("maker.near")),
+            sell_token: AccountId::new_unchecked(String::from("xabr.allbridge.testnet")),
+            sell_amount: U128(sell_amount),
+            buy_token: AccountId::new_unchecked(String::from("abr.allbridge.testnet")),
+            buy_amount: U128(buy_amount),
+            filled_sell_amount: U128(0),
+            filled_buy_amount: U128(0),
+            expires_at: None,
+            status: OrderStatus::Open,
+            storage_bytes: 0,
+        }
+    }
+
+    fn register_storage(contract: &mut Market, account_ids: &[&str]) {
+        for account_id in account_ids {
+            testing_env!(VMContextBuilder::new()
+                .attached_deposit(10u128.pow(24))
+                .build());
+            contract.storage_deposit(Some(account_id.parse().unwrap()), None);
+        }
+    }
+
+    fn list_tokens(contract: &mut Market, tokens: &[&str]) {
+        for token in tokens {
+            testing_env!(VMContextBuilder::new()
+                .predecessor_account_id(contract.owner_id.clone())
+                .attached_deposit(1)
+                .build());
+            contract.list_token(token.parse().unwrap());
+        }
+    }
+
+    fn fee_test(contract: &mut Market, token: &str, amount: u128, expect: u128) {
+        assert_eq!(
+            contract.internal_split_fee(amount, &(token.parse().unwrap())).net.0,
+            expect
+        );
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_fee_overflow() {
+        testing_env!(VMContextBuilder::new().build());
+        let mut contract = Market::new(Some(env::predecessor_account_id()));
+
         contract.set_fee("sometoken.near".parse().unwrap(), HUNDRED_PERCENT+1)
     }
 
     #[test]
     #[should_panic]
     fn test_fee_too_low() {
-        let mut contract = Market {
-            orders: UnorderedMap::new(StorageKey::Orders),
-            order_id_to_order: LookupMap::new(StorageKey::OrderIdToOrder),
-            version: 1,
-            fees: LookupMap::new(StorageKey::FeesByAccountIds)
-        };
+        testing_env!(VMContextBuilder::new().build());
+        let mut contract = Market::new(Some(env::predecessor_account_id()));
 
         contract.set_fee("sometoken.near".parse().unwrap(), 0)
     }
 
     #[test]
     fn test_fee() {
-        let mut contract = Market {
-            orders: UnorderedMap::new(StorageKey::Orders),
-            order_id_to_order: LookupMap::new(StorageKey::OrderIdToOrder),
-            version: 1,
-            fees: LookupMap::new(StorageKey::FeesByAccountIds)
-        };
+        let mut contract = Market::new(Some("aromankov.testnet".parse().unwrap()));
 
         let mut builder = VMContextBuilder::new();
         testing_env!(builder
@@ -538,12 +1311,7 @@ mod tests {
     #[test]
     #[should_panic]
     fn test_fee_wrong_permissions() {
-        let mut contract = Market {
-            orders: UnorderedMap::new(StorageKey::Orders),
-            order_id_to_order: LookupMap::new(StorageKey::OrderIdToOrder),
-            version: 1,
-            fees: LookupMap::new(StorageKey::FeesByAccountIds)
-        };
+        let mut contract = Market::new(None);
 
         let mut builder = VMContextBuilder::new();
         testing_env!(builder
@@ -555,31 +1323,183 @@ mod tests {
     }
 
     #[test]
-    fn test_order_hash() {
-        let order = create_test_order(1000000000000000000000000, 1000000000000000000000000);
-        let order1 = create_test_order(1000000000000000000000000, 1000000000000000000000000);
+    fn test_earned_fees_equal_withheld_fees() {
+        let mut contract = Market::new(None);
+        register_storage(&mut contract, &["maker.near", "taker2.near"]);
+        list_tokens(&mut contract, &["xabr.allbridge.testnet", "abr.allbridge.testnet"]);
+        let sell_token: AccountId = "xabr.allbridge.testnet".parse().unwrap();
+        let buy_token: AccountId = "abr.allbridge.testnet".parse().unwrap();
+        let maker: AccountId = "maker.near".parse().unwrap();
+        let taker: AccountId = "taker.near".parse().unwrap();
+        let taker2: AccountId = "taker2.near".parse().unwrap();
+
+        testing_env!(VMContextBuilder::new().predecessor_account_id(env::current_account_id()).build());
+        contract.set_fee(sell_token.clone(), 37);
+        contract.add_order(
+            NewOrderAction {
+                sell_token: sell_token.clone(),
+                sell_amount: U128(10000),
+                buy_token: buy_token.clone(),
+                buy_amount: U128(1000),
+                expires_at: None,
+            },
+            maker.clone(),
+            sell_token.clone(),
+            U128(10000),
+        );
+        let order_id = contract.get_orders_by_account(maker, None, None)[0].order_id;
+
+        // two fills settled through transfers, the taker payout of the second one fails
+        let mut paid_by_transfer = 0;
+        for (gross, payout) in [(330, PromiseResult::Successful(vec![])), (1230, PromiseResult::Failed)] {
+            testing_env!(
+                VMContextBuilder::new().build(),
+                Default::default(),
+                Default::default(),
+                Default::default(),
+                vec![PromiseResult::Successful(vec![])]
+            );
+            contract.callback_on_settle_fills(
+                taker.clone(),
+                sell_token.clone(),
+                buy_token.clone(),
+                vec![FillView { order_id, sell_amount: U128(gross), buy_amount: U128(gross / 10) }],
+            );
+            // what `callback_on_settle_fills` transfers to the taker
+            let net = contract.internal_split_fee(gross, &sell_token).net.0;
+            assert!(get_logs().iter().any(|log| log.contains(r#""event":"fee_collected""#)
+                && log.contains(&format!(r#""amount":"{}","gross_amount":"{}","net_amount":"{}""#, gross - net, gross, net))));
+
+            if matches!(payout, PromiseResult::Successful(_)) {
+                paid_by_transfer += net;
+            }
+            testing_env!(
+                VMContextBuilder::new().build(),
+                Default::default(),
+                Default::default(),
+                Default::default(),
+                vec![payout]
+            );
+            contract.callback_on_taker_payout(taker.clone(), sell_token.clone(), vec![(order_id, U128(net))]);
+        }
 
-        assert_eq!(order.get_id(), order1.get_id());
+        // and one settled between internal balances
+        testing_env!(VMContextBuilder::new().predecessor_account_id(buy_token.clone()).build());
+        contract.ft_on_transfer(taker2.clone(), U128(457), "".to_string());
+        testing_env!(VMContextBuilder::new().predecessor_account_id(taker2.clone()).attached_deposit(1).build());
+        contract.place_order(NewOrderAction {
+            sell_token: buy_token.clone(),
+            sell_amount: U128(457),
+            buy_token: sell_token.clone(),
+            buy_amount: U128(4570),
+            expires_at: None,
+        });
 
-        let order2 = Order {
-            maker: AccountId::new_unchecked(String::from("maker.near")),
-            sell_token: AccountId::new_unchecked(String::from("abr.allbridge.testnet")),
-            sell_amount: U128(1000000000000000000000000), // param changed
-            buy_token: AccountId::new_unchecked(String::from("xbr.allbridge.testnet")),
-            buy_amount: U128(1000000000000000000000000),
-        };
+        let paid = paid_by_transfer
+            + contract.get_balance(taker, sell_token.clone()).0
+            + contract.get_balance(taker2, sell_token.clone()).0;
+        let withheld = 330 + 1230 + 4570 - paid;
+        // 0.37% of every fill, rounded up
+        assert_eq!(withheld, 2 + 5 + 17);
+        assert_eq!(contract.get_fee_info(sell_token), Some(FeeInfo { percent: 37, earned: U128(withheld) }));
+        assert_eq!(contract.get_fee_info(buy_token), None);
+    }
+
+    #[test]
+    fn test_back_to_back_fee_withdrawals() {
+        let mut contract = Market::new(None);
+        let token: AccountId = "xabr.allbridge.testnet".parse().unwrap();
+        let treasury: AccountId = "treasury.near".parse().unwrap();
+        let earned = |contract: &Market| contract.get_fee_info(token.clone()).unwrap().earned;
+
+        testing_env!(VMContextBuilder::new()
+            .predecessor_account_id(env::current_account_id())
+            .attached_deposit(1)
+            .build());
+        contract.internal_record_fee(&OrderId(Price::new(10, 1), 0), &token, FeeSplit::from_net(1000, 900));
+
+        // the second withdrawal sees what the first one already took
+        contract.transfer_earned_fees(token.clone(), 60, treasury.clone());
+        assert_eq!(earned(&contract), U128(40));
+        contract.transfer_earned_fees(token.clone(), 40, treasury.clone());
+        assert_eq!(earned(&contract), U128(0));
+
+        // the first transfer fails and gives its amount back, the second one goes through
+        for (result, amount) in [(PromiseResult::Failed, 60), (PromiseResult::Successful(vec![]), 40)] {
+            testing_env!(
+                VMContextBuilder::new().predecessor_account_id(env::current_account_id()).build(),
+                Default::default(),
+                Default::default(),
+                Default::default(),
+                vec![result]
+            );
+            contract.callback_on_send_tokens_to_ext_account(token.clone(), treasury.clone(), U128(amount));
+            assert_eq!(earned(&contract), U128(60));
+        }
+        assert!(get_logs().iter().any(|log| log.contains(r#""event":"fee_withdrawn""#)
+            && log.contains(r#""amount":"40""#)));
+    }
+
+    #[test]
+    #[should_panic(expected = "Requires attached deposit of exactly 1 yoctoNEAR")]
+    fn test_transfer_earned_fees_requires_one_yocto() {
+        let mut contract = Market::new(None);
+        testing_env!(VMContextBuilder::new().predecessor_account_id(env::current_account_id()).build());
+        contract.transfer_earned_fees("xabr.allbridge.testnet".parse().unwrap(), 1, "treasury.near".parse().unwrap());
+    }
+
+    #[test]
+    fn test_fee_split() {
+        testing_env!(VMContextBuilder::new().build());
+        let mut contract = Market::new(None);
+        let token: AccountId = "sometoken.near".parse().unwrap();
+
+        for amount in [0, 1, 99, 100, 101, 23200, 1111111, u128::MAX] {
+            let split = contract.internal_split_fee(amount, &token);
+            assert_eq!(split.gross.0, amount);
+            assert_eq!(split.fee.0 + split.net.0, amount);
+            // the default 1%, rounded up
+            assert_eq!(split.fee.0, amount / 100 + (amount % 100 != 0) as u128);
+        }
+    }
+
+    #[test]
+    fn test_identical_orders_get_unique_ids() {
+        let mut contract = Market::new(None);
+        register_storage(&mut contract, &["maker.near"]);
+        list_tokens(&mut contract, &["xabr.allbridge.testnet", "abr.allbridge.testnet"]);
+
+        let maker: AccountId = "maker.near".parse().unwrap();
+        let sell_token: AccountId = "xabr.allbridge.testnet".parse().unwrap();
+        let buy_token: AccountId = "abr.allbridge.testnet".parse().unwrap();
+
+        testing_env!(VMContextBuilder::new().predecessor_account_id(maker.clone()).build());
+        for _ in 0..2 {
+            contract.add_order(
+                NewOrderAction {
+                    sell_token: sell_token.clone(),
+                    sell_amount: U128(1000),
+                    buy_token: buy_token.clone(),
+                    buy_amount: U128(100),
+                    expires_at: None,
+                },
+                maker.clone(),
+                sell_token.clone(),
+                U128(1000),
+            );
+        }
 
-        assert_ne!(order.get_id(), order2.get_id());
+        let orders = contract.get_orders(sell_token, buy_token, None, None).unwrap();
+        assert_eq!(orders.len(), 2);
+        assert_eq!(orders[0].order_id.0, orders[1].order_id.0);
+        assert_ne!(orders[0].order_id, orders[1].order_id);
     }
 
     #[test]
     fn test_add_order() {
-        let mut contract = Market {
-            orders: UnorderedMap::new(StorageKey::Orders),
-            order_id_to_order: LookupMap::new(StorageKey::OrderIdToOrder),
-            version: 1,
-            fees: LookupMap::new(StorageKey::FeesByAccountIds)
-        };
+        let mut contract = Market::new(None);
+        register_storage(&mut contract, &["aromankov.testnet"]);
+        list_tokens(&mut contract, &["xabr.allbridge.testnet", "abr.allbridge.testnet"]);
 
         let mut builder = VMContextBuilder::new();
         testing_env!(builder
@@ -593,33 +1513,42 @@ mod tests {
             sell_amount: U128(1000000000000000000000000),
             buy_token: AccountId::new_unchecked(String::from("abr.allbridge.testnet")),
             buy_amount: U128(1000000000000000000000000),
+            expires_at: None,
         };
 
         contract.add_order(
             new_order_action_1.clone(),
             AccountId::new_unchecked(String::from("aromankov.testnet")),
+            new_order_action_1.sell_token.clone(),
+            new_order_action_1.sell_amount,
         );
 
+        // asks more than the first order offers, so the two don't match
         let new_order_action_2 = NewOrderAction {
             sell_token: AccountId::new_unchecked(String::from("abr.allbridge.testnet")),
             sell_amount: U128(1000000000000000000000000),
             buy_token: AccountId::new_unchecked(String::from("xabr.allbridge.testnet")),
-            buy_amount: U128(1000000000000000000000000),
+            buy_amount: U128(2000000000000000000000000),
+            expires_at: None,
         };
 
         contract.add_order(
             new_order_action_2.clone(),
             AccountId::new_unchecked(String::from("aromankov.testnet")),
+            new_order_action_2.sell_token.clone(),
+            new_order_action_2.sell_amount,
         );
 
         // check get pairs
-        assert!(contract.get_pairs().len() != 0);
+        assert!(!contract.get_pairs().is_empty());
 
         // check get orders
         let orders_1 = contract
             .get_orders(
                 new_order_action_1.sell_token.clone(),
                 new_order_action_1.buy_token.clone(),
+                None,
+                None,
             )
             .unwrap();
         assert!(orders_1.len() == 1);
@@ -628,33 +1557,45 @@ mod tests {
             .get_orders(
                 new_order_action_2.sell_token.clone(),
                 new_order_action_2.buy_token.clone(),
+                None,
+                None,
             )
             .unwrap();
         assert!(orders_2.len() == 1);
 
-        let order_2 = orders_2.get(0).unwrap();
+        let order_2 = orders_2.first().unwrap();
         let order_id_2 = order_2.order_id;
         assert_eq!(*order_2, OrderView{
             order: Order {
-                buy_amount: new_order_action_2.buy_amount.clone(),
-                sell_amount: new_order_action_2.sell_amount.clone(),
+                buy_amount: new_order_action_2.buy_amount,
+                sell_amount: new_order_action_2.sell_amount,
                 buy_token: new_order_action_2.buy_token.clone(),
                 sell_token: new_order_action_2.sell_token.clone(),
-                maker: AccountId::new_unchecked(String::from("aromankov.testnet"))
+                maker: AccountId::new_unchecked(String::from("aromankov.testnet")),
+                filled_sell_amount: U128(0),
+                filled_buy_amount: U128(0),
+                expires_at: None,
+                status: OrderStatus::Open,
+                storage_bytes: order_2.order.storage_bytes,
             },
             order_id: order_id_2
         });
 
-        let order_1 = orders_1.get(0).unwrap();
+        let order_1 = orders_1.first().unwrap();
         let order_id_1 = order_1.order_id;
 
         assert_eq!(*order_1, OrderView {
             order: Order {
-                buy_amount: new_order_action_1.buy_amount.clone(),
-                sell_amount: new_order_action_1.sell_amount.clone(),
+                buy_amount: new_order_action_1.buy_amount,
+                sell_amount: new_order_action_1.sell_amount,
                 buy_token: new_order_action_1.buy_token.clone(),
                 sell_token: new_order_action_1.sell_token.clone(),
-                maker: AccountId::new_unchecked(String::from("aromankov.testnet"))
+                maker: AccountId::new_unchecked(String::from("aromankov.testnet")),
+                filled_sell_amount: U128(0),
+                filled_buy_amount: U128(0),
+                expires_at: None,
+                status: OrderStatus::Open,
+                storage_bytes: order_1.order.storage_bytes,
             },
             order_id: order_id_1
         });
@@ -662,6 +1603,8 @@ mod tests {
         // check remove order
         assert!(contract.get_order(order_id_1).is_some());
 
+        testing_env!(builder.attached_deposit(1).build());
+
         contract.remove_order(
             new_order_action_1.sell_token.clone(),
             new_order_action_1.buy_token.clone(),
@@ -674,18 +1617,15 @@ mod tests {
             order_id_2,
         );
 
-        assert!(contract.get_pairs().len() == 0);
+        assert!(contract.get_pairs().is_empty());
         assert!(contract.get_order(order_id_1).is_none());
     }
 
     #[test]
     fn test_orders_ordered_by_price() {
-        let mut contract = Market {
-            orders: UnorderedMap::new(StorageKey::Orders),
-            order_id_to_order: LookupMap::new(StorageKey::OrderIdToOrder),
-            version: 1,
-            fees: LookupMap::new(StorageKey::FeesByAccountIds)
-        };
+        let mut contract = Market::new(None);
+        register_storage(&mut contract, &["aromankov.testnet"]);
+        list_tokens(&mut contract, &["xabr.allbridge.testnet", "abr.allbridge.testnet"]);
         let mut builder = VMContextBuilder::new();
         testing_env!(builder
             .storage_usage(env::storage_usage())
@@ -701,11 +1641,14 @@ mod tests {
             sell_amount: U128(2),
             buy_token: account2.clone(),
             buy_amount: U128(1),
+            expires_at: None,
         };
 
         contract.add_order(
             new_order_action_1.clone(),
             AccountId::new_unchecked(String::from("aromankov.testnet")),
+            new_order_action_1.sell_token.clone(),
+            new_order_action_1.sell_amount,
         );
 
         let new_order_action_2 = NewOrderAction {
@@ -713,11 +1656,14 @@ mod tests {
             sell_amount: U128(1),
             buy_token: account2.clone(),
             buy_amount: U128(1),
+            expires_at: None,
         };
 
         contract.add_order(
             new_order_action_2.clone(),
             AccountId::new_unchecked(String::from("aromankov.testnet")),
+            new_order_action_2.sell_token.clone(),
+            new_order_action_2.sell_amount,
         );
 
         let new_order_action_2 = NewOrderAction {
@@ -725,29 +1671,1784 @@ mod tests {
             sell_amount: U128(1),
             buy_token: account2.clone(),
             buy_amount: U128(2),
+            expires_at: None,
         };
 
 
-        let new_order_action_3 = NewOrderAction {
-            sell_token: account1.clone(),
-            sell_amount: U128(1),
-            buy_token: account2.clone(),
-            buy_amount: U128(1),
-        };
-
         contract.add_order(
             new_order_action_2.clone(),
             AccountId::new_unchecked(String::from("aromankov.testnet")),
+            new_order_action_2.sell_token.clone(),
+            new_order_action_2.sell_amount,
         );
 
         let orders = contract
-            .get_orders(account1, account2)
+            .get_orders(account1, account2, None, None)
             .unwrap()
             .into_iter()
-            .map(|i| i.order.get_price_for_key())
+            .map(|i| i.order_id.0)
             .collect::<Vec<_>>();
 
-        assert!(orders[0] < orders[1]);
-        assert!(orders[1] < orders[2]);
+        // the best price for the taker comes first
+        assert_eq!(orders, vec![Price::new(2, 1), Price::new(1, 1), Price::new(1, 2)]);
+    }
+
+    #[test]
+    fn test_partial_fill() {
+        let mut contract = Market::new(None);
+        register_storage(&mut contract, &["maker.near"]);
+        list_tokens(&mut contract, &["xabr.allbridge.testnet", "abr.allbridge.testnet"]);
+        let builder = VMContextBuilder::new();
+        testing_env!(
+            builder.build(),
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            vec![PromiseResult::Successful(vec![])]
+        );
+
+        let sell_token: AccountId = "xabr.allbridge.testnet".parse().unwrap();
+        let buy_token: AccountId = "abr.allbridge.testnet".parse().unwrap();
+
+        contract.add_order(
+            NewOrderAction {
+                sell_token: sell_token.clone(),
+                sell_amount: U128(1000),
+                buy_token: buy_token.clone(),
+                buy_amount: U128(100),
+                expires_at: None,
+            },
+            "maker.near".parse().unwrap(),
+            sell_token.clone(),
+            U128(1000),
+        );
+        let order_id = contract
+            .get_orders(sell_token.clone(), buy_token.clone(), None, None)
+            .unwrap()[0]
+            .order_id;
+
+        let order = contract.get_order(order_id).unwrap();
+        assert_eq!(order.get_sell_amount_for(30), 300);
+
+        contract.callback_on_settle_fills(
+            "taker.near".parse().unwrap(),
+            sell_token.clone(),
+            buy_token.clone(),
+            vec![FillView { order_id, sell_amount: U128(300), buy_amount: U128(30) }],
+        );
+
+        assert!(get_logs().iter().any(|log| log.starts_with("EVENT_JSON:")
+            && log.contains(r#""event":"order_filled""#)
+            && log.contains(r#""taker":"taker.near""#)
+            && log.contains(r#""is_partial":true"#)));
+
+        let order = contract.get_order(order_id).unwrap();
+        assert_eq!(order.sell_amount, U128(700));
+        assert_eq!(order.buy_amount, U128(70));
+        assert_eq!(order.filled_sell_amount, U128(300));
+        assert_eq!(order.filled_buy_amount, U128(30));
+
+        let orders = contract.get_orders(sell_token.clone(), buy_token.clone(), None, None).unwrap();
+        assert_eq!(orders, vec![OrderView { order, order_id }]);
+
+        contract.callback_on_settle_fills(
+            "taker.near".parse().unwrap(),
+            sell_token,
+            buy_token,
+            vec![FillView { order_id, sell_amount: U128(700), buy_amount: U128(70) }],
+        );
+
+        assert!(contract.get_order(order_id).is_none());
+        assert!(contract.get_pairs().is_empty());
+    }
+
+    #[test]
+    fn test_sell_amount_for_partial_fill() {
+        let order = create_test_order(3, 2);
+        assert_eq!(order.get_sell_amount_for(1), 1);
+        assert_eq!(order.get_sell_amount_for(2), 3);
+
+        // the intermediate product does not fit into u128
+        let order = create_test_order(1000000000000000000000000000, 3000000000000000000000000);
+        assert_eq!(order.get_sell_amount_for(1000000000000000000000000), 333333333333333333333333333);
+        assert_eq!(order.get_sell_amount_for(3000000000000000000000000), 1000000000000000000000000000);
+    }
+
+    #[test]
+    fn test_add_order_refunds_excess() {
+        let mut contract = Market::new(None);
+        register_storage(&mut contract, &["maker.near"]);
+        list_tokens(&mut contract, &["xabr.allbridge.testnet", "abr.allbridge.testnet"]);
+        testing_env!(VMContextBuilder::new().build());
+
+        let sell_token: AccountId = "xabr.allbridge.testnet".parse().unwrap();
+        let buy_token: AccountId = "abr.allbridge.testnet".parse().unwrap();
+
+        let excess = contract.add_order(
+            NewOrderAction {
+                sell_token: sell_token.clone(),
+                sell_amount: U128(1000),
+                buy_token: buy_token.clone(),
+                buy_amount: U128(100),
+                expires_at: None,
+            },
+            "maker.near".parse().unwrap(),
+            sell_token.clone(),
+            U128(1200),
+        );
+        assert_eq!(excess, U128(200));
+
+        let orders = contract.get_orders(sell_token, buy_token, None, None).unwrap();
+        assert_eq!(orders[0].order.sell_amount, U128(1000));
+    }
+
+    #[test]
+    fn test_crossing_remainder_is_not_placed() {
+        let mut contract = Market::new(None);
+        register_storage(&mut contract, &["maker1.near", "maker2.near", "taker.near"]);
+        list_tokens(&mut contract, &["xabr.allbridge.testnet", "abr.allbridge.testnet"]);
+        testing_env!(VMContextBuilder::new().build());
+
+        let token_a: AccountId = "xabr.allbridge.testnet".parse().unwrap();
+        let token_b: AccountId = "abr.allbridge.testnet".parse().unwrap();
+        for maker in ["maker1.near", "maker2.near"] {
+            contract.add_order(
+                NewOrderAction {
+                    sell_token: token_b.clone(),
+                    sell_amount: U128(100),
+                    buy_token: token_a.clone(),
+                    buy_amount: U128(100),
+                    expires_at: None,
+                },
+                maker.parse().unwrap(),
+                token_b.clone(),
+                U128(100),
+            );
+        }
+
+        // gas for a single fill, the second maker order still crosses what is left
+        testing_env!(VMContextBuilder::new()
+            .prepaid_gas(Gas(RESERVE_TGAS.0 + SETTLE_CALLBACK_TGAS.0 + MATCH_TGAS.0 + MATCH_TGAS.0 / 2))
+            .build());
+        let excess = contract.add_order(
+            NewOrderAction {
+                sell_token: token_a.clone(),
+                sell_amount: U128(200),
+                buy_token: token_b.clone(),
+                buy_amount: U128(200),
+                expires_at: None,
+            },
+            "taker.near".parse().unwrap(),
+            token_a.clone(),
+            U128(200),
+        );
+        assert_eq!(excess, U128(100));
+        assert!(contract.get_orders(token_a, token_b, None, None).is_none());
+    }
+
+    #[test]
+    fn test_new_order_matches_crossing_orders() {
+        let mut contract = Market::new(None);
+        register_storage(&mut contract, &["maker.near", "taker1.near", "taker2.near"]);
+        list_tokens(&mut contract, &["xabr.allbridge.testnet", "abr.allbridge.testnet"]);
+        testing_env!(VMContextBuilder::new().build());
+
+        let token_a: AccountId = "xabr.allbridge.testnet".parse().unwrap();
+        let token_b: AccountId = "abr.allbridge.testnet".parse().unwrap();
+
+        // maker sells 1000 B for 100 A, i.e. 10 B per A
+        contract.add_order(
+            NewOrderAction {
+                sell_token: token_b.clone(),
+                sell_amount: U128(1000),
+                buy_token: token_a.clone(),
+                buy_amount: U128(100),
+                expires_at: None,
+            },
+            "maker.near".parse().unwrap(),
+            token_b.clone(),
+            U128(1000),
+        );
+
+        // doesn't cross: asks for 12 B per A
+        contract.add_order(
+            NewOrderAction {
+                sell_token: token_a.clone(),
+                sell_amount: U128(50),
+                buy_token: token_b.clone(),
+                buy_amount: U128(600),
+                expires_at: None,
+            },
+            "taker1.near".parse().unwrap(),
+            token_a.clone(),
+            U128(50),
+        );
+        assert_eq!(contract.get_orders(token_a.clone(), token_b.clone(), None, None).unwrap().len(), 1);
+        assert!(get_created_receipts().is_empty());
+
+        // crosses: asks for 8 B per A, takes the whole maker order and rests the rest
+        let excess = contract.add_order(
+            NewOrderAction {
+                sell_token: token_a.clone(),
+                sell_amount: U128(150),
+                buy_token: token_b.clone(),
+                buy_amount: U128(1200),
+                expires_at: None,
+            },
+            "taker2.near".parse().unwrap(),
+            token_a.clone(),
+            U128(150),
+        );
+        assert_eq!(excess, U128(0));
+        // ft_transfer to the maker and the settle callback
+        assert_eq!(get_created_receipts().len(), 2);
+
+        let orders = contract.get_orders(token_a.clone(), token_b.clone(), None, None).unwrap();
+        assert_eq!(orders.len(), 2);
+        let rested = orders
+            .into_iter()
+            .map(|view| view.order)
+            .find(|order| order.maker.as_str() == "taker2.near")
+            .unwrap();
+        assert_eq!(rested.sell_amount, U128(50));
+        assert_eq!(rested.buy_amount, U128(400));
+        assert_eq!(rested.filled_sell_amount, U128(100));
+        assert_eq!(rested.filled_buy_amount, U128(1000));
+
+        // the maker order is updated once the settlement callbacks run
+        assert_eq!(contract.get_orders(token_b, token_a, None, None).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_market_buy_sweeps_the_book() {
+        let mut contract = Market::new(None);
+        register_storage(&mut contract, &["maker1.near", "maker2.near", "maker3.near"]);
+        list_tokens(&mut contract, &["xabr.allbridge.testnet", "abr.allbridge.testnet"]);
+        testing_env!(VMContextBuilder::new().build());
+
+        let token_a: AccountId = "xabr.allbridge.testnet".parse().unwrap();
+        let token_b: AccountId = "abr.allbridge.testnet".parse().unwrap();
+
+        let makers = [("maker1.near", 1000, 100), ("maker2.near", 400, 50), ("maker3.near", 600, 100)];
+        for (maker, sell_amount, buy_amount) in makers {
+            contract.add_order(
+                NewOrderAction {
+                    sell_token: token_b.clone(),
+                    sell_amount: U128(sell_amount),
+                    buy_token: token_a.clone(),
+                    buy_amount: U128(buy_amount),
+                    expires_at: None,
+                },
+                maker.parse().unwrap(),
+                token_b.clone(),
+                U128(sell_amount),
+            );
+        }
+
+        // about what `ft_on_transfer` gets out of a 300 TGas `ft_transfer_call`
+        testing_env!(VMContextBuilder::new()
+            .prepaid_gas(Gas(270_000_000_000_000))
+            .build());
+        // all three orders, each minus 1% fee
+        let unspent = contract.market_buy(
+            "taker.near".parse().unwrap(),
+            token_a.clone(),
+            U128(300),
+            token_b.clone(),
+            U128(1980),
+        );
+        assert_eq!(unspent, U128(50));
+
+        // the three maker payments and a single settle callback waiting for all of them
+        let receipts = get_created_receipts();
+        assert_eq!(receipts.len(), 4);
+        assert!(receipts[..3].iter().all(|receipt| receipt.receiver_id == token_a));
+        assert_eq!(receipts[3].receiver_id, env::current_account_id());
+        assert_eq!(receipts[3].receipt_indices, vec![0, 1, 2]);
+        let orders = contract.get_orders(token_b, token_a, None, None).unwrap();
+        assert!(orders.iter().all(|view| view.order.status == OrderStatus::Settling));
+    }
+
+    #[test]
+    fn test_settle_fills_pays_and_refunds_the_taker_once() {
+        let mut contract = Market::new(None);
+        register_storage(&mut contract, &["maker1.near", "maker2.near"]);
+        list_tokens(&mut contract, &["xabr.allbridge.testnet", "abr.allbridge.testnet"]);
+        testing_env!(VMContextBuilder::new().build());
+
+        let token_a: AccountId = "xabr.allbridge.testnet".parse().unwrap();
+        let token_b: AccountId = "abr.allbridge.testnet".parse().unwrap();
+        let taker: AccountId = "taker.near".parse().unwrap();
+        for maker in ["maker1.near", "maker2.near"] {
+            contract.add_order(
+                NewOrderAction {
+                    sell_token: token_b.clone(),
+                    sell_amount: U128(1000),
+                    buy_token: token_a.clone(),
+                    buy_amount: U128(100),
+                    expires_at: None,
+                },
+                maker.parse().unwrap(),
+                token_b.clone(),
+                U128(1000),
+            );
+        }
+        let order_ids: Vec<OrderId> = contract
+            .get_orders(token_b.clone(), token_a.clone(), None, None)
+            .unwrap()
+            .into_iter()
+            .map(|view| view.order_id)
+            .collect();
+        contract.market_buy(taker.clone(), token_a.clone(), U128(200), token_b.clone(), U128(0));
+
+        // the first maker got paid, the payment to the second one failed
+        testing_env!(
+            VMContextBuilder::new().build(),
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            vec![PromiseResult::Successful(vec![]), PromiseResult::Failed]
+        );
+        let fills = order_ids
+            .iter()
+            .map(|order_id| FillView { order_id: *order_id, sell_amount: U128(1000), buy_amount: U128(100) })
+            .collect();
+        contract.callback_on_settle_fills(taker, token_b.clone(), token_a.clone(), fills);
+
+        assert!(contract.get_order(order_ids[0]).is_none());
+        assert_eq!(contract.get_order(order_ids[1]).unwrap().status, OrderStatus::Open);
+        // the payout in B and the refund in A, each with its callback
+        let receipts = get_created_receipts();
+        assert_eq!(receipts.len(), 4);
+        assert_eq!((&receipts[0].receiver_id, &receipts[2].receiver_id), (&token_b, &token_a));
+    }
+
+    #[test]
+    fn test_failed_maker_payment_refunds_taker() {
+        let mut contract = Market::new(None);
+        register_storage(&mut contract, &["maker.near"]);
+        list_tokens(&mut contract, &["xabr.allbridge.testnet", "abr.allbridge.testnet"]);
+        testing_env!(VMContextBuilder::new().build());
+
+        let sell_token: AccountId = "xabr.allbridge.testnet".parse().unwrap();
+        let buy_token: AccountId = "abr.allbridge.testnet".parse().unwrap();
+        let taker: AccountId = "taker.near".parse().unwrap();
+
+        contract.add_order(
+            NewOrderAction {
+                sell_token: sell_token.clone(),
+                sell_amount: U128(1000),
+                buy_token: buy_token.clone(),
+                buy_amount: U128(100),
+                expires_at: None,
+            },
+            "maker.near".parse().unwrap(),
+            sell_token.clone(),
+            U128(1000),
+        );
+        let order_id = contract
+            .get_orders(sell_token.clone(), buy_token.clone(), None, None)
+            .unwrap()[0]
+            .order_id;
+
+        testing_env!(
+            VMContextBuilder::new().build(),
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            vec![PromiseResult::Failed]
+        );
+
+        contract.callback_on_settle_fills(
+            taker.clone(),
+            sell_token,
+            buy_token.clone(),
+            vec![FillView { order_id, sell_amount: U128(300), buy_amount: U128(30) }],
+        );
+
+        // the order is untouched, the taker's payment goes back with a refund callback
+        let order = contract.get_order(order_id).unwrap();
+        assert_eq!(order.sell_amount, U128(1000));
+        assert_eq!(order.buy_amount, U128(100));
+        assert_eq!(get_created_receipts().len(), 2);
+
+        // the refund failed as well
+        contract.callback_on_refund(taker.clone(), buy_token.clone(), U128(30));
+        assert_eq!(contract.get_balance(taker, buy_token), U128(30));
+    }
+
+    /// Starts the settlement of a fill of the maker.near order by taker.near paying `amount`.
+    fn start_settling_fill(contract: &mut Market, amount: u128) {
+        testing_env!(VMContextBuilder::new().build());
+        contract.market_buy(
+            "taker.near".parse().unwrap(),
+            "abr.allbridge.testnet".parse().unwrap(),
+            U128(amount),
+            "xabr.allbridge.testnet".parse().unwrap(),
+            U128(0),
+        );
+    }
+
+    /// Places a 1000 / 100 order of maker.near.
+    fn place_maker_order(contract: &mut Market) -> OrderId {
+        register_storage(contract, &["maker.near"]);
+        list_tokens(contract, &["xabr.allbridge.testnet", "abr.allbridge.testnet"]);
+        testing_env!(VMContextBuilder::new().build());
+
+        let sell_token: AccountId = "xabr.allbridge.testnet".parse().unwrap();
+        let buy_token: AccountId = "abr.allbridge.testnet".parse().unwrap();
+        contract.add_order(
+            NewOrderAction {
+                sell_token: sell_token.clone(),
+                sell_amount: U128(1000),
+                buy_token: buy_token.clone(),
+                buy_amount: U128(100),
+                expires_at: None,
+            },
+            "maker.near".parse().unwrap(),
+            sell_token,
+            U128(1000),
+        );
+        contract.get_orders_by_account("maker.near".parse().unwrap(), None, None)[0].order_id
+    }
+
+    /// Places a 1000 / 100 order of maker.near and starts the settlement of a 30 fill by taker.near.
+    fn start_settling_order(contract: &mut Market) -> OrderId {
+        let order_id = place_maker_order(contract);
+        start_settling_fill(contract, 30);
+        order_id
+    }
+
+    #[test]
+    fn test_order_locked_while_settling() {
+        let mut contract = Market::new(None);
+        let order_id = start_settling_order(&mut contract);
+        let sell_token: AccountId = "xabr.allbridge.testnet".parse().unwrap();
+        let buy_token: AccountId = "abr.allbridge.testnet".parse().unwrap();
+        let maker: AccountId = "maker.near".parse().unwrap();
+        let taker: AccountId = "taker.near".parse().unwrap();
+
+        let order = contract.get_order(order_id).unwrap();
+        assert_eq!(order.status, OrderStatus::Settling);
+        assert_eq!(contract.orders.get(&compose_key(&sell_token, &buy_token)).unwrap().get(&order_id), Some(order));
+
+        // neither swept by other takers nor cancelled in batches
+        assert!(contract.internal_collect_fills(&compose_key(&sell_token, &buy_token), 100, false, |_| true).is_empty());
+        testing_env!(VMContextBuilder::new().predecessor_account_id(maker.clone()).attached_deposit(1).build());
+        assert_eq!(contract.cancel_all_orders(None), CancelOrdersResult { cancelled: vec![], remaining: 0 });
+        assert!(contract.get_order(order_id).is_some());
+
+        // the maker payment failed, the order is open again
+        testing_env!(
+            VMContextBuilder::new().build(),
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            vec![PromiseResult::Failed]
+        );
+        contract.callback_on_settle_fills(
+            taker.clone(),
+            sell_token.clone(),
+            buy_token.clone(),
+            vec![FillView { order_id, sell_amount: U128(300), buy_amount: U128(30) }],
+        );
+        assert_eq!(contract.get_order(order_id).unwrap().status, OrderStatus::Open);
+
+        // a partial fill settles and reopens the order
+        start_settling_fill(&mut contract, 30);
+        testing_env!(
+            VMContextBuilder::new().build(),
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            vec![PromiseResult::Successful(vec![])]
+        );
+        contract.callback_on_settle_fills(
+            taker.clone(),
+            sell_token.clone(),
+            buy_token.clone(),
+            vec![FillView { order_id, sell_amount: U128(300), buy_amount: U128(30) }],
+        );
+        let order = contract.get_order(order_id).unwrap();
+        assert_eq!((order.status, order.buy_amount), (OrderStatus::Open, U128(70)));
+
+        // the rest fills it, it leaves the book as filled
+        start_settling_fill(&mut contract, 70);
+        testing_env!(
+            VMContextBuilder::new().build(),
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            vec![PromiseResult::Successful(vec![])]
+        );
+        contract.callback_on_settle_fills(
+            taker,
+            sell_token,
+            buy_token,
+            vec![FillView { order_id, sell_amount: U128(700), buy_amount: U128(70) }],
+        );
+        assert!(contract.get_order(order_id).is_none());
+        assert!(get_logs()
+            .iter()
+            .any(|log| log.contains(r#""event":"order_filled""#) && log.contains(r#""status":"Filled""#)));
+    }
+
+    #[test]
+    #[should_panic(expected = "E22: order has a settlement in progress")]
+    fn test_settling_order_cannot_be_matched_again() {
+        let mut contract = Market::new(None);
+        let order_id = start_settling_order(&mut contract);
+
+        contract.match_order("taker2.near".parse().unwrap(), order_id, U128(30), "abr.allbridge.testnet".parse().unwrap());
+    }
+
+    #[test]
+    fn test_match_order_locks_the_order() {
+        let mut contract = Market::new(None);
+        let order_id = place_maker_order(&mut contract);
+
+        testing_env!(VMContextBuilder::new().prepaid_gas(Gas(300_000_000_000_000)).build());
+        contract.match_order("taker.near".parse().unwrap(), order_id, U128(30), "abr.allbridge.testnet".parse().unwrap());
+        assert_eq!(contract.get_order(order_id).unwrap().status, OrderStatus::Settling);
+    }
+
+    #[test]
+    #[should_panic(expected = "E23: not enough gas attached for the settlement")]
+    fn test_match_order_requires_gas_for_the_settlement() {
+        let mut contract = Market::new(None);
+        let order_id = place_maker_order(&mut contract);
+
+        testing_env!(VMContextBuilder::new().prepaid_gas(MATCH_TGAS).build());
+        contract.match_order("taker.near".parse().unwrap(), order_id, U128(30), "abr.allbridge.testnet".parse().unwrap());
+    }
+
+    #[test]
+    fn test_owner_reopens_settling_order() {
+        let mut contract = Market::new(None);
+        let order_id = start_settling_order(&mut contract);
+
+        testing_env!(VMContextBuilder::new()
+            .predecessor_account_id(env::current_account_id())
+            .attached_deposit(1)
+            .build());
+        contract.reopen_order(order_id);
+        assert_eq!(contract.get_order(order_id).unwrap().status, OrderStatus::Open);
+        assert_eq!(contract.internal_collect_fills(
+            &compose_key(&"xabr.allbridge.testnet".parse().unwrap(), &"abr.allbridge.testnet".parse().unwrap()),
+            100,
+            false,
+            |_| true,
+        ).len(), 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "E24: order has no settlement in progress")]
+    fn test_reopen_order_requires_settling_order() {
+        let mut contract = Market::new(None);
+        let order_id = place_maker_order(&mut contract);
+
+        testing_env!(VMContextBuilder::new()
+            .predecessor_account_id(env::current_account_id())
+            .attached_deposit(1)
+            .build());
+        contract.reopen_order(order_id);
+    }
+
+    #[test]
+    #[should_panic(expected = "E22: order has a settlement in progress")]
+    fn test_settling_order_cannot_be_removed() {
+        let mut contract = Market::new(None);
+        let order_id = start_settling_order(&mut contract);
+
+        testing_env!(VMContextBuilder::new()
+            .predecessor_account_id("maker.near".parse().unwrap())
+            .attached_deposit(1)
+            .build());
+        contract.remove_order(
+            "xabr.allbridge.testnet".parse().unwrap(),
+            "abr.allbridge.testnet".parse().unwrap(),
+            order_id,
+        );
+    }
+
+    #[test]
+    fn test_remove_order_refund() {
+        let mut contract = Market::new(None);
+        register_storage(&mut contract, &["maker.near"]);
+        list_tokens(&mut contract, &["xabr.allbridge.testnet", "abr.allbridge.testnet"]);
+        let maker: AccountId = "maker.near".parse().unwrap();
+        let sell_token: AccountId = "xabr.allbridge.testnet".parse().unwrap();
+        let buy_token: AccountId = "abr.allbridge.testnet".parse().unwrap();
+
+        testing_env!(VMContextBuilder::new().build());
+        contract.add_order(
+            NewOrderAction {
+                sell_token: sell_token.clone(),
+                sell_amount: U128(1000),
+                buy_token: buy_token.clone(),
+                buy_amount: U128(100),
+                expires_at: None,
+            },
+            maker.clone(),
+            sell_token.clone(),
+            U128(1000),
+        );
+        let order_id = contract.get_orders_by_account(maker.clone(), None, None)[0].order_id;
+
+        testing_env!(VMContextBuilder::new().predecessor_account_id(maker.clone()).attached_deposit(1).build());
+        contract.remove_order(sell_token.clone(), buy_token, order_id);
+        assert!(contract.get_order(order_id).is_none());
+        assert!(get_logs().iter().any(|log| log.starts_with("EVENT_JSON:")
+            && log.contains(r#""event":"order_cancelled""#)
+            && log.contains(r#""sell_amount":"1000""#)
+            && log.contains(r#""status":"Cancelled""#)));
+        // the refund and its callback
+        assert_eq!(get_created_receipts().len(), 2);
+
+        // the maker unregistered from the token, the refund can be withdrawn later
+        testing_env!(
+            VMContextBuilder::new().predecessor_account_id(env::current_account_id()).build(),
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            vec![PromiseResult::Failed]
+        );
+        contract.callback_on_cancel_refund(order_id, maker.clone(), sell_token.clone(), U128(1000));
+        assert_eq!(contract.get_balance(maker, sell_token), U128(1000));
+    }
+
+    #[test]
+    #[should_panic(expected = "Requires attached deposit of exactly 1 yoctoNEAR")]
+    fn test_remove_order_requires_one_yocto() {
+        let mut contract = Market::new(None);
+        testing_env!(VMContextBuilder::new().predecessor_account_id("maker.near".parse().unwrap()).build());
+        contract.remove_order(
+            "xabr.allbridge.testnet".parse().unwrap(),
+            "abr.allbridge.testnet".parse().unwrap(),
+            OrderId(Price::new(10, 1), 0),
+        );
+    }
+
+    #[test]
+    fn test_failed_taker_payout_credits_balance() {
+        let mut contract = Market::new(None);
+        register_storage(&mut contract, &["maker.near"]);
+        list_tokens(&mut contract, &["xabr.allbridge.testnet", "abr.allbridge.testnet"]);
+        testing_env!(VMContextBuilder::new().build());
+
+        let sell_token: AccountId = "xabr.allbridge.testnet".parse().unwrap();
+        let buy_token: AccountId = "abr.allbridge.testnet".parse().unwrap();
+        let taker: AccountId = "taker.near".parse().unwrap();
+
+        contract.add_order(
+            NewOrderAction {
+                sell_token: sell_token.clone(),
+                sell_amount: U128(1000),
+                buy_token: buy_token.clone(),
+                buy_amount: U128(100),
+                expires_at: None,
+            },
+            "maker.near".parse().unwrap(),
+            sell_token.clone(),
+            U128(1000),
+        );
+        let order_id = contract
+            .get_orders(sell_token.clone(), buy_token.clone(), None, None)
+            .unwrap()[0]
+            .order_id;
+
+        testing_env!(
+            VMContextBuilder::new().build(),
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            vec![PromiseResult::Successful(vec![])]
+        );
+        contract.callback_on_settle_fills(
+            taker.clone(),
+            sell_token.clone(),
+            buy_token,
+            vec![FillView { order_id, sell_amount: U128(300), buy_amount: U128(30) }],
+        );
+
+        testing_env!(
+            VMContextBuilder::new().build(),
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            vec![PromiseResult::Failed]
+        );
+        contract.callback_on_taker_payout(taker.clone(), sell_token.clone(), vec![(order_id, U128(297))]);
+
+        // the maker has been paid, so the fill stands
+        let order = contract.get_order(order_id).unwrap();
+        assert_eq!(order.sell_amount, U128(700));
+        assert_eq!(contract.get_balance(taker, sell_token), U128(297));
+        assert!(get_logs().iter().any(|log| log.contains(r#""event":"settlement_failed""#)));
+    }
+
+    #[test]
+    fn test_orders_from_internal_balance() {
+        let mut contract = Market::new(None);
+        register_storage(&mut contract, &["maker.near", "taker.near"]);
+        list_tokens(&mut contract, &["xabr.allbridge.testnet", "abr.allbridge.testnet"]);
+        let token_a: AccountId = "xabr.allbridge.testnet".parse().unwrap();
+        let token_b: AccountId = "abr.allbridge.testnet".parse().unwrap();
+        let maker: AccountId = "maker.near".parse().unwrap();
+        let taker: AccountId = "taker.near".parse().unwrap();
+
+        // deposits with an empty msg
+        testing_env!(VMContextBuilder::new().predecessor_account_id(token_b.clone()).build());
+        contract.ft_on_transfer(maker.clone(), U128(1000), "".to_string());
+        testing_env!(VMContextBuilder::new().predecessor_account_id(token_a.clone()).build());
+        contract.ft_on_transfer(taker.clone(), U128(100), "".to_string());
+        assert_eq!(contract.get_balance(maker.clone(), token_b.clone()), U128(1000));
+        assert_eq!(contract.get_balances(taker.clone()), vec![(token_a.clone(), U128(100))]);
+
+        // maker sells 1000 B for 100 A
+        testing_env!(VMContextBuilder::new()
+            .predecessor_account_id(maker.clone())
+            .attached_deposit(1)
+            .build());
+        contract.place_order(NewOrderAction {
+            sell_token: token_b.clone(),
+            sell_amount: U128(1000),
+            buy_token: token_a.clone(),
+            buy_amount: U128(100),
+            expires_at: None,
+        });
+        assert_eq!(contract.get_balance(maker.clone(), token_b.clone()), U128(0));
+
+        // taker crosses it with half of their balance, settled without any transfer
+        testing_env!(VMContextBuilder::new()
+            .predecessor_account_id(taker.clone())
+            .attached_deposit(1)
+            .build());
+        contract.place_order(NewOrderAction {
+            sell_token: token_a.clone(),
+            sell_amount: U128(50),
+            buy_token: token_b.clone(),
+            buy_amount: U128(400),
+            expires_at: None,
+        });
+        assert!(get_created_receipts().is_empty());
+
+        assert_eq!(contract.get_balance(maker.clone(), token_a.clone()), U128(50));
+        assert_eq!(contract.get_balance(taker.clone(), token_a.clone()), U128(50));
+        // 500 B minus 1% fee
+        assert_eq!(contract.get_balance(taker.clone(), token_b.clone()), U128(495));
+
+        let order = &contract.get_orders(token_b.clone(), token_a.clone(), None, None).unwrap()[0].order;
+        assert_eq!(order.sell_amount, U128(500));
+        assert_eq!(order.buy_amount, U128(50));
+        assert!(contract.get_orders(token_a.clone(), token_b.clone(), None, None).is_none());
+
+        contract.withdraw(token_b.clone(), U128(495));
+        assert_eq!(contract.get_balance(taker.clone(), token_b.clone()), U128(0));
+        // ft_transfer and the callback restoring the balance on failure
+        assert_eq!(get_created_receipts().len(), 2);
+
+        testing_env!(
+            VMContextBuilder::new().build(),
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            vec![PromiseResult::Failed]
+        );
+        contract.callback_on_refund(taker.clone(), token_b.clone(), U128(495));
+        assert_eq!(contract.get_balance(taker, token_b), U128(495));
+    }
+
+    #[test]
+    fn test_deposit_requires_registration_and_listing() {
+        let mut contract = Market::new(None);
+        register_storage(&mut contract, &["taker.near"]);
+        list_tokens(&mut contract, &["xabr.allbridge.testnet"]);
+        let listed: AccountId = "xabr.allbridge.testnet".parse().unwrap();
+        let unlisted: AccountId = "abr.allbridge.testnet".parse().unwrap();
+        let taker: AccountId = "taker.near".parse().unwrap();
+        let stranger: AccountId = "stranger.near".parse().unwrap();
+
+        let refunded = |result: PromiseOrValue<U128>| match result {
+            PromiseOrValue::Value(refund) => refund.0,
+            PromiseOrValue::Promise(_) => panic!("expected a value"),
+        };
+
+        testing_env!(VMContextBuilder::new().predecessor_account_id(listed.clone()).build());
+        assert_eq!(refunded(contract.ft_on_transfer(stranger.clone(), U128(100), "".to_string())), 100);
+        assert!(contract.get_balances(stranger).is_empty());
+
+        testing_env!(VMContextBuilder::new().predecessor_account_id(unlisted.clone()).build());
+        assert_eq!(refunded(contract.ft_on_transfer(taker.clone(), U128(100), "".to_string())), 100);
+        assert_eq!(contract.get_balance(taker.clone(), unlisted), U128(0));
+
+        testing_env!(VMContextBuilder::new().predecessor_account_id(listed.clone()).build());
+        assert_eq!(refunded(contract.ft_on_transfer(taker.clone(), U128(100), "".to_string())), 0);
+        assert_eq!(contract.get_balance(taker, listed), U128(100));
+    }
+
+    #[test]
+    fn test_place_order_sweeps_the_book_on_balances() {
+        let mut contract = Market::new(None);
+        register_storage(&mut contract, &["maker.near", "taker.near"]);
+        list_tokens(&mut contract, &["xabr.allbridge.testnet", "abr.allbridge.testnet"]);
+        let token_a: AccountId = "xabr.allbridge.testnet".parse().unwrap();
+        let token_b: AccountId = "abr.allbridge.testnet".parse().unwrap();
+        let maker: AccountId = "maker.near".parse().unwrap();
+        let taker: AccountId = "taker.near".parse().unwrap();
+
+        // ten orders selling 100 B for 10 A each, more than a transfer settlement fits
+        for _ in 0..10 {
+            testing_env!(VMContextBuilder::new().predecessor_account_id(token_b.clone()).build());
+            contract.add_order(
+                NewOrderAction {
+                    sell_token: token_b.clone(),
+                    sell_amount: U128(100),
+                    buy_token: token_a.clone(),
+                    buy_amount: U128(10),
+                    expires_at: None,
+                },
+                maker.clone(),
+                token_b.clone(),
+                U128(100),
+            );
+        }
+
+        testing_env!(VMContextBuilder::new().predecessor_account_id(token_a.clone()).build());
+        contract.ft_on_transfer(taker.clone(), U128(100), "".to_string());
+
+        testing_env!(VMContextBuilder::new()
+            .predecessor_account_id(taker.clone())
+            .attached_deposit(1)
+            .prepaid_gas(Gas(300_000_000_000_000))
+            .build());
+        contract.place_order(NewOrderAction {
+            sell_token: token_a.clone(),
+            sell_amount: U128(100),
+            buy_token: token_b.clone(),
+            buy_amount: U128(1000),
+            expires_at: None,
+        });
+        assert!(get_created_receipts().is_empty());
+
+        assert!(contract.get_orders(token_b.clone(), token_a.clone(), None, None).is_none());
+        assert_eq!(contract.get_balance(maker, token_a), U128(100));
+        // 10 fills of 100 B minus 1% fee
+        assert_eq!(contract.get_balance(taker, token_b), U128(990));
+    }
+
+    #[test]
+    fn test_storage_charged_for_orders() {
+        let mut contract = Market::new(None);
+        register_storage(&mut contract, &["maker.near"]);
+        list_tokens(&mut contract, &["xabr.allbridge.testnet", "abr.allbridge.testnet"]);
+
+        let maker: AccountId = "maker.near".parse().unwrap();
+        let sell_token: AccountId = "xabr.allbridge.testnet".parse().unwrap();
+        let buy_token: AccountId = "abr.allbridge.testnet".parse().unwrap();
+
+        let balance = contract.storage_balance_of(maker.clone()).unwrap();
+        assert_eq!(balance.total, U128(10u128.pow(24)));
+        assert_eq!(
+            balance.available.0,
+            10u128.pow(24) - contract.storage_balance_bounds().min.0
+        );
+
+        testing_env!(VMContextBuilder::new().predecessor_account_id(maker.clone()).build());
+        contract.add_order(
+            NewOrderAction {
+                sell_token: sell_token.clone(),
+                sell_amount: U128(1000),
+                buy_token: buy_token.clone(),
+                buy_amount: U128(100),
+                expires_at: None,
+            },
+            maker.clone(),
+            sell_token.clone(),
+            U128(1000),
+        );
+        let charged = contract.storage_balance_of(maker.clone()).unwrap().available;
+        assert!(charged.0 < balance.available.0);
+
+        let order_id = contract
+            .get_orders(sell_token.clone(), buy_token.clone(), None, None)
+            .unwrap()[0]
+            .order_id;
+        testing_env!(VMContextBuilder::new().predecessor_account_id(maker.clone()).attached_deposit(1).build());
+        contract.remove_order(sell_token, buy_token, order_id);
+
+        assert_eq!(contract.storage_balance_of(maker.clone()).unwrap().available, balance.available);
+
+        testing_env!(VMContextBuilder::new()
+            .predecessor_account_id(maker.clone())
+            .attached_deposit(1)
+            .build());
+        assert!(contract.storage_unregister(None));
+        assert!(contract.storage_balance_of(maker).is_none());
+    }
+
+    #[test]
+    fn test_storage_released_per_order() {
+        let mut contract = Market::new(None);
+        register_storage(&mut contract, &["m1.near", "m2.near"]);
+        list_tokens(&mut contract, &["xabr.allbridge.testnet", "abr.allbridge.testnet"]);
+
+        let sell_token: AccountId = "xabr.allbridge.testnet".parse().unwrap();
+        let buy_token: AccountId = "abr.allbridge.testnet".parse().unwrap();
+        let makers: Vec<AccountId> = vec!["m1.near".parse().unwrap(), "m2.near".parse().unwrap()];
+        let available = contract.storage_balance_of(makers[0].clone()).unwrap().available;
+
+        // the first order also creates the book of the pair
+        for maker in makers.iter() {
+            testing_env!(VMContextBuilder::new().predecessor_account_id(sell_token.clone()).build());
+            contract.add_order(
+                NewOrderAction {
+                    sell_token: sell_token.clone(),
+                    sell_amount: U128(1000),
+                    buy_token: buy_token.clone(),
+                    buy_amount: U128(100),
+                    expires_at: None,
+                },
+                maker.clone(),
+                sell_token.clone(),
+                U128(1000),
+            );
+        }
+
+        // the maker who created the book cancels first
+        for maker in makers.iter() {
+            let order_id = contract.get_orders_by_account(maker.clone(), None, None)[0].order_id;
+            testing_env!(VMContextBuilder::new()
+                .predecessor_account_id(maker.clone())
+                .attached_deposit(1)
+                .build());
+            contract.remove_order(sell_token.clone(), buy_token.clone(), order_id);
+        }
+
+        for maker in makers {
+            assert_eq!(contract.storage_balance_of(maker.clone()).unwrap().available, available);
+            testing_env!(VMContextBuilder::new()
+                .predecessor_account_id(maker.clone())
+                .attached_deposit(1)
+                .build());
+            assert!(contract.storage_unregister(None));
+        }
+    }
+
+    #[test]
+    fn test_cleanup_expired() {
+        let mut contract = Market::new(None);
+        register_storage(&mut contract, &["maker.near"]);
+        list_tokens(&mut contract, &["xabr.allbridge.testnet", "abr.allbridge.testnet"]);
+
+        let maker: AccountId = "maker.near".parse().unwrap();
+        let sell_token: AccountId = "xabr.allbridge.testnet".parse().unwrap();
+        let buy_token: AccountId = "abr.allbridge.testnet".parse().unwrap();
+        let key = compose_key(&sell_token, &buy_token);
+
+        testing_env!(VMContextBuilder::new()
+            .predecessor_account_id(maker.clone())
+            .block_timestamp(100)
+            .build());
+        for (sell_amount, expires_at) in [(1000, Some(U64(200))), (2000, None)] {
+            contract.add_order(
+                NewOrderAction {
+                    sell_token: sell_token.clone(),
+                    sell_amount: U128(sell_amount),
+                    buy_token: buy_token.clone(),
+                    buy_amount: U128(100),
+                    expires_at,
+                },
+                maker.clone(),
+                sell_token.clone(),
+                U128(sell_amount),
+            );
+        }
+        let available = contract.storage_balance_of(maker.clone()).unwrap().available;
+
+        // nothing is expired yet
+        assert_eq!(contract.cleanup_expired(key.clone(), None, None), CleanupResult { removed: 0, next_index: None });
+
+        testing_env!(VMContextBuilder::new()
+            .predecessor_account_id("keeper.near".parse().unwrap())
+            .block_timestamp(200)
+            .build());
+        // the better priced order that doesn't expire is read first
+        let best_id = contract.get_orders(sell_token.clone(), buy_token.clone(), None, Some(1)).unwrap()[0].order_id;
+        assert_eq!(
+            contract.cleanup_expired(key.clone(), None, Some(1)),
+            CleanupResult { removed: 0, next_index: Some(best_id) }
+        );
+        assert_eq!(
+            contract.cleanup_expired(key.clone(), Some(best_id), Some(1)),
+            CleanupResult { removed: 1, next_index: None }
+        );
+
+        let orders = contract.get_orders(sell_token.clone(), buy_token, None, None).unwrap();
+        assert_eq!(orders.len(), 1);
+        assert_eq!(orders[0].order.sell_amount, U128(2000));
+        assert_eq!(contract.get_balance(maker.clone(), sell_token), U128(1000));
+
+        // the keeper got the storage deposit of the removed order
+        assert_eq!(contract.storage_balance_of(maker).unwrap().available, available);
+        let receipts = get_created_receipts();
+        assert_eq!(receipts.len(), 1);
+        assert_eq!(receipts[0].receiver_id, "keeper.near".parse::<AccountId>().unwrap());
+    }
+
+    #[test]
+    fn test_get_orders_pagination() {
+        let mut contract = Market::new(None);
+        register_storage(&mut contract, &["maker.near"]);
+        list_tokens(&mut contract, &["xabr.allbridge.testnet", "abr.allbridge.testnet"]);
+
+        let maker: AccountId = "maker.near".parse().unwrap();
+        let sell_token: AccountId = "xabr.allbridge.testnet".parse().unwrap();
+        let buy_token: AccountId = "abr.allbridge.testnet".parse().unwrap();
+
+        testing_env!(VMContextBuilder::new().predecessor_account_id(maker.clone()).build());
+        for buy_amount in 1..=7 {
+            contract.add_order(
+                NewOrderAction {
+                    sell_token: sell_token.clone(),
+                    sell_amount: U128(1000),
+                    buy_token: buy_token.clone(),
+                    buy_amount: U128(buy_amount),
+                    expires_at: None,
+                },
+                maker.clone(),
+                sell_token.clone(),
+                U128(1000),
+            );
+        }
+        assert_eq!(contract.get_orders_count(sell_token.clone(), buy_token.clone()), 7);
+        assert_eq!(contract.get_orders_count(buy_token.clone(), sell_token.clone()), 0);
+
+        let mut pages = vec![];
+        let mut from_index = None;
+        loop {
+            let page = contract
+                .get_orders(sell_token.clone(), buy_token.clone(), from_index, Some(3))
+                .unwrap();
+            if page.is_empty() {
+                break;
+            }
+            from_index = Some(page.last().unwrap().order_id);
+            pages.push(page);
+        }
+
+        assert_eq!(pages.iter().map(|page| page.len()).collect::<Vec<_>>(), vec![3, 3, 1]);
+        let buy_amounts: Vec<u128> = pages
+            .into_iter()
+            .flatten()
+            .map(|view| view.order.buy_amount.0)
+            .collect();
+        assert_eq!(buy_amounts, (1..=7).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_get_orders_by_account() {
+        let mut contract = Market::new(None);
+        register_storage(&mut contract, &["maker.near", "taker.near"]);
+        list_tokens(&mut contract, &["a.near", "b.near", "c.near"]);
+
+        let maker: AccountId = "maker.near".parse().unwrap();
+        let taker: AccountId = "taker.near".parse().unwrap();
+        let token_a: AccountId = "a.near".parse().unwrap();
+        let token_b: AccountId = "b.near".parse().unwrap();
+        let token_c: AccountId = "c.near".parse().unwrap();
+
+        testing_env!(VMContextBuilder::new().predecessor_account_id(maker.clone()).build());
+        for buy_token in [&token_b, &token_c] {
+            contract.add_order(
+                NewOrderAction {
+                    sell_token: token_a.clone(),
+                    sell_amount: U128(1000),
+                    buy_token: buy_token.clone(),
+                    buy_amount: U128(100),
+                    expires_at: None,
+                },
+                maker.clone(),
+                token_a.clone(),
+                U128(1000),
+            );
+        }
+
+        let orders = contract.get_orders_by_account(maker.clone(), None, None);
+        assert_eq!(orders.len(), 2);
+        assert!(orders.iter().all(|view| view.order.maker == maker));
+        assert_eq!(contract.get_orders_by_account(maker.clone(), Some(1), Some(5)).len(), 1);
+        assert!(contract.get_orders_by_account(taker.clone(), None, None).is_empty());
+
+        // a filled order leaves the index
+        testing_env!(VMContextBuilder::new()
+            .predecessor_account_id(taker.clone())
+            .attached_deposit(1)
+            .build());
+        contract.internal_deposit(&taker, &token_b, 100);
+        contract.place_order(NewOrderAction {
+            sell_token: token_b,
+            sell_amount: U128(100),
+            buy_token: token_a.clone(),
+            buy_amount: U128(1000),
+            expires_at: None,
+        });
+        let orders = contract.get_orders_by_account(maker.clone(), None, None);
+        assert_eq!(orders.len(), 1);
+        assert_eq!(orders[0].order.buy_token, token_c);
+
+        // and so does a cancelled one
+        testing_env!(VMContextBuilder::new().predecessor_account_id(maker.clone()).attached_deposit(1).build());
+        contract.remove_order(token_a, token_c, orders[0].order_id);
+        assert!(contract.get_orders_by_account(maker, None, None).is_empty());
+    }
+
+    #[test]
+    fn test_cancel_orders() {
+        let mut contract = Market::new(None);
+        register_storage(&mut contract, &["maker.near"]);
+        list_tokens(&mut contract, &["a.near", "b.near", "c.near"]);
+
+        let maker: AccountId = "maker.near".parse().unwrap();
+        let token_a: AccountId = "a.near".parse().unwrap();
+        let token_b: AccountId = "b.near".parse().unwrap();
+        let token_c: AccountId = "c.near".parse().unwrap();
+
+        testing_env!(VMContextBuilder::new().predecessor_account_id(maker.clone()).build());
+        for (sell_token, buy_token) in [(&token_a, &token_b), (&token_a, &token_c), (&token_c, &token_a)] {
+            contract.add_order(
+                NewOrderAction {
+                    sell_token: sell_token.clone(),
+                    sell_amount: U128(1000),
+                    buy_token: buy_token.clone(),
+                    buy_amount: U128(10000),
+                    expires_at: None,
+                },
+                maker.clone(),
+                sell_token.clone(),
+                U128(1000),
+            );
+        }
+        let order_ids: Vec<OrderId> = contract
+            .get_orders_by_account(maker.clone(), None, None)
+            .into_iter()
+            .map(|view| view.order_id)
+            .collect();
+
+        // enough gas for the first two orders and one refund only
+        testing_env!(VMContextBuilder::new()
+            .predecessor_account_id(maker.clone())
+            .attached_deposit(1)
+            .prepaid_gas(Gas(2 * REFUND_TGAS.0 + CANCEL_ORDER_TGAS.0))
+            .build());
+        let result = contract.cancel_orders(order_ids.clone());
+        assert_eq!(result, CancelOrdersResult { cancelled: order_ids[..2].to_vec(), remaining: 1 });
+
+        // a single transfer refunds both orders selling `token_a`
+        let receipts = get_created_receipts();
+        assert_eq!(receipts.len(), 2);
+        assert_eq!(receipts[0].receiver_id, token_a);
+
+        testing_env!(VMContextBuilder::new()
+            .predecessor_account_id(maker.clone())
+            .attached_deposit(1)
+            .build());
+        let result = contract.cancel_all_orders(Some(compose_key(&token_c, &token_a)));
+        assert_eq!(result, CancelOrdersResult { cancelled: vec![order_ids[2]], remaining: 0 });
+        assert!(contract.get_orders_by_account(maker, None, None).is_empty());
+    }
+
+    #[test]
+    fn test_order_book() {
+        let mut contract = Market::new(None);
+        register_storage(&mut contract, &["maker.near"]);
+        list_tokens(&mut contract, &["a.near", "b.near"]);
+
+        let maker: AccountId = "maker.near".parse().unwrap();
+        let base: AccountId = "a.near".parse().unwrap();
+        let quote: AccountId = "b.near".parse().unwrap();
+
+        testing_env!(VMContextBuilder::new().predecessor_account_id(maker.clone()).build());
+        let orders = [
+            // asks
+            (&base, 100, &quote, 250),
+            (&base, 200, &quote, 500),
+            (&base, 100, &quote, 300),
+            // bids
+            (&quote, 200, &base, 100),
+            (&quote, 100, &base, 300),
+        ];
+        for (sell_token, sell_amount, buy_token, buy_amount) in orders {
+            contract.add_order(
+                NewOrderAction {
+                    sell_token: sell_token.clone(),
+                    sell_amount: U128(sell_amount),
+                    buy_token: buy_token.clone(),
+                    buy_amount: U128(buy_amount),
+                    expires_at: None,
+                },
+                maker.clone(),
+                sell_token.clone(),
+                U128(sell_amount),
+            );
+        }
+
+        let level = |price: &str, quantity: u128, orders: u64| PriceLevel {
+            price: price.to_string(),
+            quantity: U128(quantity),
+            orders,
+        };
+        assert_eq!(
+            contract.get_order_book(base.clone(), quote.clone(), None),
+            OrderBookView {
+                bids: vec![level("2", 100, 1), level("0.333333333333333333", 300, 1)],
+                asks: vec![level("2.5", 300, 2), level("3", 100, 1)],
+                best_bid: Some("2".to_string()),
+                best_ask: Some("2.5".to_string()),
+                spread: Some("0.5".to_string()),
+            }
+        );
+
+        let book = contract.get_order_book(base, quote, Some(1));
+        assert_eq!(book.asks, vec![level("2.5", 300, 2)]);
+        assert_eq!(book.bids, vec![level("2", 100, 1)]);
+    }
+
+    #[test]
+    fn test_order_book_spread_with_large_amounts() {
+        let mut contract = Market::new(None);
+        register_storage(&mut contract, &["maker.near"]);
+        list_tokens(&mut contract, &["a.near", "b.near"]);
+
+        let maker: AccountId = "maker.near".parse().unwrap();
+        let base: AccountId = "a.near".parse().unwrap();
+        let quote: AccountId = "b.near".parse().unwrap();
+
+        // 24 decimals tokens with coprime amounts, the spread denominator takes about 200 bits
+        let unit = 10u128.pow(30);
+        testing_env!(VMContextBuilder::new().predecessor_account_id(maker.clone()).build());
+        let orders = [(&base, unit + 1, &quote, unit * 3 / 2 + 1), (&quote, unit + 3, &base, unit + 7)];
+        for (sell_token, sell_amount, buy_token, buy_amount) in orders {
+            contract.add_order(
+                NewOrderAction {
+                    sell_token: sell_token.clone(),
+                    sell_amount: U128(sell_amount),
+                    buy_token: buy_token.clone(),
+                    buy_amount: U128(buy_amount),
+                    expires_at: None,
+                },
+                maker.clone(),
+                sell_token.clone(),
+                U128(sell_amount),
+            );
+        }
+
+        let book = contract.get_order_book(base, quote, None);
+        assert_eq!(book.best_ask, Some("1.499999999999999999".to_string()));
+        assert_eq!(book.best_bid, Some("0.999999999999999999".to_string()));
+        assert_eq!(book.spread, Some("0.5".to_string()));
+    }
+
+    #[test]
+    fn test_trade_history() {
+        let mut contract = Market::new(None);
+        register_storage(&mut contract, &["maker.near", "taker.near"]);
+        list_tokens(&mut contract, &["a.near", "b.near"]);
+
+        let maker: AccountId = "maker.near".parse().unwrap();
+        let taker: AccountId = "taker.near".parse().unwrap();
+        let token_a: AccountId = "a.near".parse().unwrap();
+        let token_b: AccountId = "b.near".parse().unwrap();
+        let pair = compose_key(&token_a, &token_b);
+
+        testing_env!(VMContextBuilder::new().predecessor_account_id(maker.clone()).build());
+        contract.add_order(
+            NewOrderAction {
+                sell_token: token_a.clone(),
+                sell_amount: U128(1000),
+                buy_token: token_b.clone(),
+                buy_amount: U128(100),
+                expires_at: None,
+            },
+            maker.clone(),
+            token_a.clone(),
+            U128(1000),
+        );
+        assert!(contract.get_last_price(pair.clone()).is_none());
+
+        testing_env!(VMContextBuilder::new()
+            .predecessor_account_id(taker.clone())
+            .attached_deposit(1)
+            .block_timestamp(42)
+            .build());
+        contract.internal_deposit(&taker, &token_b, 40);
+        contract.place_order(NewOrderAction {
+            sell_token: token_b,
+            sell_amount: U128(40),
+            buy_token: token_a,
+            buy_amount: U128(400),
+            expires_at: None,
+        });
+
+        let trades = contract.get_trades(pair.clone(), None, None);
+        assert_eq!(trades.len(), 1);
+        assert_eq!(trades[0].maker, maker);
+        assert_eq!(trades[0].taker, taker);
+        assert_eq!(trades[0].side, Side::Buy);
+        assert_eq!((trades[0].base_amount, trades[0].quote_amount), (U128(400), U128(40)));
+        assert_eq!(trades[0].timestamp, U64(42));
+        assert_eq!(contract.get_last_price(pair.clone()), Some(Price::new(1, 10)));
+
+        // only the last trades are kept, newest first
+        let trade = trades[0].clone();
+        for amount in 1..=history::MAX_TRADES_PER_PAIR + 5 {
+            testing_env!(VMContextBuilder::new().build());
+            contract.internal_record_trade(
+                &pair,
+                Trade { base_amount: U128(amount as u128), ..trade.clone() },
+            );
+        }
+        let amounts = |trades: Vec<Trade>| trades.iter().map(|trade| trade.base_amount.0).collect::<Vec<_>>();
+        assert_eq!(amounts(contract.get_trades(pair.clone(), None, Some(3))), vec![105, 104, 103]);
+        assert_eq!(amounts(contract.get_trades(pair.clone(), Some(98), None)), vec![7, 6]);
+    }
+
+    #[test]
+    fn test_trades_of_both_sides_share_the_pair() {
+        let mut contract = Market::new(None);
+        register_storage(&mut contract, &["maker.near", "taker.near"]);
+        list_tokens(&mut contract, &["a.near", "b.near"]);
+
+        let maker: AccountId = "maker.near".parse().unwrap();
+        let taker: AccountId = "taker.near".parse().unwrap();
+        let token_a: AccountId = "a.near".parse().unwrap();
+        let token_b: AccountId = "b.near".parse().unwrap();
+
+        testing_env!(VMContextBuilder::new().predecessor_account_id(maker.clone()).build());
+        for (sell_token, sell_amount, buy_token, buy_amount) in
+            [(&token_a, 1000, &token_b, 100), (&token_b, 100, &token_a, 2000)]
+        {
+            contract.add_order(
+                NewOrderAction {
+                    sell_token: sell_token.clone(),
+                    sell_amount: U128(sell_amount),
+                    buy_token: buy_token.clone(),
+                    buy_amount: U128(buy_amount),
+                    expires_at: None,
+                },
+                maker.clone(),
+                sell_token.clone(),
+                U128(sell_amount),
+            );
+        }
+
+        // the taker buys the base token, then sells it
+        for (timestamp, sell_token, sell_amount, buy_token, buy_amount) in
+            [(1, &token_b, 40, &token_a, 400), (2, &token_a, 1000, &token_b, 50)]
+        {
+            testing_env!(VMContextBuilder::new()
+                .predecessor_account_id(taker.clone())
+                .attached_deposit(1)
+                .block_timestamp(timestamp)
+                .build());
+            contract.internal_deposit(&taker, sell_token, sell_amount);
+            contract.place_order(NewOrderAction {
+                sell_token: sell_token.clone(),
+                sell_amount: U128(sell_amount),
+                buy_token: buy_token.clone(),
+                buy_amount: U128(buy_amount),
+                expires_at: None,
+            });
+        }
+
+        let trades = contract.get_trades("a.near#b.near".to_string(), None, None);
+        assert_eq!(trades, contract.get_trades("b.near#a.near".to_string(), None, None));
+        let sides = trades
+            .iter()
+            .map(|trade| (trade.side, trade.base_amount.0, trade.quote_amount.0, trade.price))
+            .collect::<Vec<_>>();
+        assert_eq!(sides, vec![(Side::Sell, 1000, 50, Price::new(1, 20)), (Side::Buy, 400, 40, Price::new(1, 10))]);
+        assert_eq!(contract.get_last_price("a.near#b.near".to_string()), Some(Price::new(1, 20)));
+        assert_eq!(contract.get_last_price("b.near#a.near".to_string()), Some(Price::new(1, 20)));
+
+        // both fills go into the same candle
+        let candles = contract.get_candles("b.near#a.near".to_string(), Resolution::Hour, None, None);
+        assert_eq!(candles, contract.get_candles("a.near#b.near".to_string(), Resolution::Hour, None, None));
+        assert_eq!(
+            candles,
+            vec![Candle {
+                start: U64(0),
+                open: Price::new(1, 10),
+                high: Price::new(1, 10),
+                low: Price::new(1, 20),
+                close: Price::new(1, 20),
+                base_volume: U128(1400),
+                quote_volume: U128(90),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_candles() {
+        let mut contract = Market::new(None);
+        let pair = "a.near#b.near".to_string();
+        let minute = 60 * 1_000_000_000;
+
+        for (timestamp, quote_amount, base_amount) in [(0, 100, 10), (30, 120, 10), (50, 80, 10), (70, 90, 10)] {
+            testing_env!(VMContextBuilder::new().build());
+            contract.internal_record_trade(
+                &pair,
+                Trade {
+                    order_id: OrderId(Price::new(1, 1), 0),
+                    maker: "maker.near".parse().unwrap(),
+                    taker: "taker.near".parse().unwrap(),
+                    side: Side::Buy,
+                    base_amount: U128(base_amount),
+                    quote_amount: U128(quote_amount),
+                    price: Price::new(quote_amount, base_amount),
+                    timestamp: U64(timestamp * minute),
+                },
+            );
+        }
+
+        let hourly = contract.get_candles(pair.clone(), Resolution::Hour, None, None);
+        assert_eq!(
+            hourly,
+            vec![
+                Candle {
+                    start: U64(0),
+                    open: Price::new(10, 1),
+                    high: Price::new(12, 1),
+                    low: Price::new(8, 1),
+                    close: Price::new(8, 1),
+                    base_volume: U128(30),
+                    quote_volume: U128(300),
+                },
+                Candle {
+                    start: U64(60 * minute),
+                    open: Price::new(9, 1),
+                    high: Price::new(9, 1),
+                    low: Price::new(9, 1),
+                    close: Price::new(9, 1),
+                    base_volume: U128(10),
+                    quote_volume: U128(90),
+                },
+            ]
+        );
+        assert_eq!(
+            contract.get_candles(pair.clone(), Resolution::Hour, Some(U64(65 * minute)), None),
+            hourly[1..].to_vec()
+        );
+
+        let daily = contract.get_candles(pair, Resolution::Day, None, None);
+        assert_eq!(daily.len(), 1);
+        assert_eq!((daily[0].high, daily[0].low), (Price::new(12, 1), Price::new(8, 1)));
+        assert_eq!(daily[0].quote_volume, U128(390));
+    }
+
+    #[test]
+    fn test_roles() {
+        let owner: AccountId = "owner.near".parse().unwrap();
+        let manager: AccountId = "manager.near".parse().unwrap();
+        let mut contract = Market::new(Some(owner.clone()));
+
+        testing_env!(VMContextBuilder::new()
+            .predecessor_account_id(owner.clone())
+            .attached_deposit(1)
+            .build());
+        contract.grant_role(manager.clone(), Role::FeeManager);
+        contract.grant_role(manager.clone(), Role::Pauser);
+        assert_eq!(contract.get_roles(manager.clone()), vec![Role::FeeManager, Role::Pauser]);
+
+        testing_env!(VMContextBuilder::new().predecessor_account_id(manager.clone()).build());
+        contract.set_fee("sometoken.near".parse().unwrap(), 500);
+        fee_test(&mut contract, "sometoken.near", 100, 95);
+
+        testing_env!(VMContextBuilder::new()
+            .predecessor_account_id(owner.clone())
+            .attached_deposit(1)
+            .build());
+        contract.revoke_role(manager.clone(), Role::FeeManager);
+        assert_eq!(contract.get_roles(manager.clone()), vec![Role::Pauser]);
+
+        contract.transfer_ownership(manager.clone());
+        assert_eq!(contract.get_owner(), owner);
+        assert_eq!(contract.get_pending_owner(), Some(manager.clone()));
+
+        testing_env!(VMContextBuilder::new().predecessor_account_id(manager.clone()).build());
+        contract.accept_ownership();
+        assert_eq!(contract.get_owner(), manager);
+        assert!(contract.get_pending_owner().is_none());
+    }
+
+    #[test]
+    #[should_panic(expected = "E04: permission denied")]
+    fn test_revoked_role_cannot_set_fee() {
+        let owner: AccountId = "owner.near".parse().unwrap();
+        let manager: AccountId = "manager.near".parse().unwrap();
+        let mut contract = Market::new(Some(owner.clone()));
+
+        testing_env!(VMContextBuilder::new()
+            .predecessor_account_id(owner)
+            .attached_deposit(1)
+            .build());
+        contract.grant_role(manager.clone(), Role::FeeManager);
+        contract.revoke_role(manager.clone(), Role::FeeManager);
+
+        testing_env!(VMContextBuilder::new().predecessor_account_id(manager).build());
+        contract.set_fee("sometoken.near".parse().unwrap(), 500);
+    }
+
+    #[test]
+    #[should_panic(expected = "Requires attached deposit of exactly 1 yoctoNEAR")]
+    fn test_pause_requires_one_yocto() {
+        let mut contract = Market::new(None);
+        testing_env!(VMContextBuilder::new().predecessor_account_id(env::current_account_id()).build());
+        contract.pause();
+    }
+
+    #[test]
+    fn test_pause_and_halt() {
+        let owner: AccountId = "owner.near".parse().unwrap();
+        let maker: AccountId = "maker.near".parse().unwrap();
+        let token_a: AccountId = "a.near".parse().unwrap();
+        let token_b: AccountId = "b.near".parse().unwrap();
+        let token_c: AccountId = "c.near".parse().unwrap();
+        let mut contract = Market::new(Some(owner.clone()));
+        register_storage(&mut contract, &["maker.near"]);
+        list_tokens(&mut contract, &["a.near", "b.near", "c.near"]);
+
+        let order_msg = |buy_token: &AccountId| {
+            format!(
+                r#"{{"sell_token":"a.near","sell_amount":"1000","buy_token":"{}","buy_amount":"100"}}"#,
+                buy_token
+            )
+        };
+        let refunded = |result: PromiseOrValue<U128>| match result {
+            PromiseOrValue::Value(refund) => refund.0,
+            PromiseOrValue::Promise(_) => panic!("expected a value"),
+        };
+
+        testing_env!(VMContextBuilder::new()
+            .predecessor_account_id(owner.clone())
+            .attached_deposit(1)
+            .build());
+        contract.pause();
+        assert!(contract.is_paused());
+
+        testing_env!(VMContextBuilder::new().predecessor_account_id(token_a.clone()).build());
+        assert_eq!(refunded(contract.ft_on_transfer(maker.clone(), U128(1000), order_msg(&token_b))), 1000);
+        assert!(contract.get_orders_by_account(maker.clone(), None, None).is_empty());
+
+        // halting the reverse pair halts both directions
+        testing_env!(VMContextBuilder::new()
+            .predecessor_account_id(owner)
+            .attached_deposit(1)
+            .build());
+        contract.unpause();
+        contract.halt_pair(compose_key(&token_b, &token_a));
+        assert_eq!(contract.get_halted_pairs(), vec!["b.near#a.near".to_string()]);
+
+        testing_env!(VMContextBuilder::new().predecessor_account_id(token_a.clone()).build());
+        assert_eq!(refunded(contract.ft_on_transfer(maker.clone(), U128(1000), order_msg(&token_b))), 1000);
+        assert_eq!(refunded(contract.ft_on_transfer(maker.clone(), U128(1000), order_msg(&token_c))), 0);
+
+        // cancellations keep working
+        let order_id = contract.get_orders_by_account(maker.clone(), None, None)[0].order_id;
+        testing_env!(VMContextBuilder::new().predecessor_account_id(maker.clone()).attached_deposit(1).build());
+        contract.remove_order(token_a, token_c, order_id);
+        assert!(contract.get_orders_by_account(maker, None, None).is_empty());
+    }
+
+    #[test]
+    fn test_token_listing() {
+        let owner: AccountId = "owner.near".parse().unwrap();
+        let proposer: AccountId = "proposer.near".parse().unwrap();
+        let token_a: AccountId = "a.near".parse().unwrap();
+        let token_b: AccountId = "b.near".parse().unwrap();
+        let mut contract = Market::new(Some(owner.clone()));
+
+        testing_env!(VMContextBuilder::new()
+            .predecessor_account_id(owner.clone())
+            .attached_deposit(1)
+            .build());
+        contract.set_listing_bond(Some(U128(100)));
+
+        for token in [&token_a, &token_b] {
+            testing_env!(VMContextBuilder::new()
+                .predecessor_account_id(proposer.clone())
+                .attached_deposit(100)
+                .build());
+            contract.propose_token(token.clone());
+        }
+        assert_eq!(
+            contract.get_token(token_a.clone()),
+            Some(TokenInfo { status: TokenStatus::Proposed, proposer: Some(proposer.clone()), bond: U128(100) })
+        );
+
+        // approval lists the token and returns the bond
+        testing_env!(VMContextBuilder::new()
+            .predecessor_account_id(owner.clone())
+            .attached_deposit(1)
+            .build());
+        contract.approve_token(token_a.clone());
+        assert_eq!(contract.get_token(token_a.clone()).unwrap().status, TokenStatus::Listed);
+        assert_eq!(get_created_receipts()[0].receiver_id, proposer);
+
+        // a slashed bond goes to the owner
+        testing_env!(VMContextBuilder::new()
+            .predecessor_account_id(owner.clone())
+            .attached_deposit(1)
+            .build());
+        contract.slash_token(token_b.clone());
+        assert!(contract.get_token(token_b).is_none());
+        assert_eq!(get_created_receipts()[0].receiver_id, owner);
+
+        contract.delist_token(token_a.clone());
+        assert_eq!(contract.get_tokens(None, None), vec![(token_a, TokenInfo {
+            status: TokenStatus::Delisted,
+            proposer: None,
+            bond: U128(0),
+        })]);
+    }
+
+    #[test]
+    #[should_panic(expected = "E25: token is waiting for approval, approve or slash it")]
+    fn test_delist_proposed_token() {
+        let owner: AccountId = "owner.near".parse().unwrap();
+        let token: AccountId = "a.near".parse().unwrap();
+        let mut contract = Market::new(Some(owner.clone()));
+
+        testing_env!(VMContextBuilder::new()
+            .predecessor_account_id(owner.clone())
+            .attached_deposit(1)
+            .build());
+        contract.set_listing_bond(Some(U128(100)));
+
+        testing_env!(VMContextBuilder::new()
+            .predecessor_account_id("proposer.near".parse().unwrap())
+            .attached_deposit(100)
+            .build());
+        contract.propose_token(token.clone());
+
+        testing_env!(VMContextBuilder::new()
+            .predecessor_account_id(owner)
+            .attached_deposit(1)
+            .build());
+        contract.delist_token(token);
+    }
+
+    #[test]
+    #[should_panic(expected = "E17: token is not listed")]
+    fn test_order_with_unlisted_token() {
+        let mut contract = Market::new(None);
+        register_storage(&mut contract, &["maker.near"]);
+        list_tokens(&mut contract, &["a.near"]);
+
+        testing_env!(VMContextBuilder::new().predecessor_account_id("maker.near".parse().unwrap()).build());
+        contract.add_order(
+            NewOrderAction {
+                sell_token: "a.near".parse().unwrap(),
+                sell_amount: U128(1000),
+                buy_token: "b.near".parse().unwrap(),
+                buy_amount: U128(100),
+                expires_at: None,
+            },
+            "maker.near".parse().unwrap(),
+            "a.near".parse().unwrap(),
+            U128(1000),
+        );
+    }
+
+    #[test]
+    fn test_token_metadata() {
+        let mut contract = Market::new(None);
+        register_storage(&mut contract, &["maker.near"]);
+        list_tokens(&mut contract, &["a.near", "b.near"]);
+
+        let maker: AccountId = "maker.near".parse().unwrap();
+        let token_a: AccountId = "a.near".parse().unwrap();
+        let token_b: AccountId = "b.near".parse().unwrap();
+
+        testing_env!(VMContextBuilder::new().predecessor_account_id(maker.clone()).build());
+        contract.add_order(
+            NewOrderAction {
+                sell_token: token_a.clone(),
+                sell_amount: U128(1_500_000),
+                buy_token: token_b.clone(),
+                buy_amount: U128(3 * 10u128.pow(24)),
+                expires_at: None,
+            },
+            maker.clone(),
+            token_a.clone(),
+            U128(1_500_000),
+        );
+        assert!(contract.get_orders_normalized(token_a.clone(), token_b.clone(), None, None).is_none());
+
+        contract.register_token(token_a.clone());
+        assert_eq!(get_created_receipts()[0].receiver_id, token_a);
+
+        let metadata = |symbol: &str, decimals: u8, icon: Option<&str>| FungibleTokenMetadata {
+            spec: "ft-1.0.0".to_string(),
+            name: symbol.to_string(),
+            symbol: symbol.to_string(),
+            icon: icon.map(|icon| icon.to_string()),
+            reference: None,
+            reference_hash: None,
+            decimals,
+        };
+        for (token, metadata) in [(&token_a, metadata("A", 6, None)), (&token_b, metadata("B", 24, Some("data:b")))] {
+            testing_env!(
+                VMContextBuilder::new()
+                    .predecessor_account_id(env::current_account_id())
+                    .build(),
+                Default::default(),
+                Default::default(),
+                Default::default(),
+                vec![PromiseResult::Successful(serde_json::to_vec(&metadata).unwrap())]
+            );
+            assert!(contract.callback_on_ft_metadata(token.clone()));
+        }
+        assert_eq!(
+            contract.get_token_metadata(token_b.clone()),
+            Some(TokenMetadata {
+                symbol: "B".to_string(),
+                decimals: 24,
+                icon_hash: Some(hex::encode(env::sha256(b"data:b"))),
+            })
+        );
+
+        let orders = contract.get_orders_normalized(token_a.clone(), token_b, None, None).unwrap();
+        assert_eq!(
+            (orders[0].sell_amount.as_str(), orders[0].buy_amount.as_str(), orders[0].price.as_str()),
+            ("1.5", "3", "2")
+        );
+        assert_eq!(orders[0].sell_symbol, "A");
+
+        // a failed fetch keeps what was cached
+        testing_env!(
+            VMContextBuilder::new()
+                .predecessor_account_id(env::current_account_id())
+                .build(),
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            vec![PromiseResult::Failed]
+        );
+        assert!(!contract.callback_on_ft_metadata(token_a.clone()));
+        assert_eq!(contract.get_token_metadata(token_a.clone()).unwrap().decimals, 6);
+
+        // so do decimals too large for the normalized views
+        testing_env!(
+            VMContextBuilder::new()
+                .predecessor_account_id(env::current_account_id())
+                .build(),
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            vec![PromiseResult::Successful(serde_json::to_vec(&metadata("A", 78, None)).unwrap())]
+        );
+        assert!(!contract.callback_on_ft_metadata(token_a.clone()));
+        assert_eq!(contract.get_token_metadata(token_a).unwrap().decimals, 6);
     }
 }
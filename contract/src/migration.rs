@@ -0,0 +1,229 @@
+//! Upgrades the state written by older versions of the contract. New code is
+//! deployed together with a `migrate` call, see `redeploy.sh`.
+
+use crate::legacy::{LegacyOrderBook, MarketV1, OrderV1, OrderV2};
+use crate::tokens::TokenStatus;
+use crate::*;
+
+/// Key `near_bindgen` keeps the contract state under.
+const STATE_KEY: &[u8] = b"STATE";
+
+#[near_bindgen]
+impl Market {
+    /// Converts the state of any older version to the current layout, the
+    /// version is the first byte of the state. A no-op on the current version.
+    #[private]
+    #[init(ignore_state)]
+    pub fn migrate() -> Self {
+        let state = env::storage_read(STATE_KEY).unwrap_or_else(|| env::panic_str(ERR01_INTERNAL));
+        match state.first() {
+            Some(1) => Self::migrate_from_v1(
+                MarketV1::try_from_slice(&state).unwrap_or_else(|_| env::panic_str(ERR01_INTERNAL)),
+            ),
+            Some(2) => {
+                let mut market = Self::try_from_slice(&state).unwrap_or_else(|_| env::panic_str(ERR01_INTERNAL));
+                market.migrate_orders_from_v2();
+                market
+            }
+            Some(&STATE_VERSION) => {
+                Self::try_from_slice(&state).unwrap_or_else(|_| env::panic_str(ERR01_INTERNAL))
+            }
+            _ => env::panic_str(ERR21_UNKNOWN_STATE_VERSION),
+        }
+    }
+}
+
+impl Market {
+    /// Rewrites every book with the current order layout and nonce based ids and
+    /// builds the account index. The fees keep their prefix and layout. Tokens of
+    /// the open orders are listed, version 1 had no registry.
+    fn migrate_from_v1(mut old: MarketV1) -> Self {
+        let mut market = Self::new(None);
+
+        // the books are drained first, the new ones reuse the prefixes of the pairs
+        let books: Vec<(String, LegacyOrderBook<OrderV1>)> = old.orders.to_vec();
+        old.orders.clear();
+
+        for (pair, mut book) in books {
+            let mut orders_map = TreeMap::new(pair.as_bytes());
+            for (old_id, order) in book.drain() {
+                old.order_id_to_order.remove(&old_id);
+
+                let order = Order::from(order);
+                for token in [&order.sell_token, &order.buy_token] {
+                    if market.tokens.get(token).is_none() {
+                        market.internal_set_token_status(token, TokenStatus::Listed);
+                    }
+                }
+
+                let order_id = market.internal_next_order_id(&order);
+                orders_map.insert(&order_id, &order);
+                market.order_id_to_order.insert(&order_id, &order);
+                market.internal_add_account_order(&order.maker, &order_id);
+            }
+            if !orders_map.is_empty() {
+                market.orders.insert(&pair, &orders_map);
+            }
+        }
+
+        market
+    }
+
+    /// Version 2 differs only in the `Order` layout, the books keep their ids
+    /// and tree structure, only the values are rewritten. They are written raw,
+    /// the typed maps would fail reading the old values they replace.
+    fn migrate_orders_from_v2(&mut self) {
+        self.version = STATE_VERSION;
+
+        for pair in self.orders.keys_as_vector().to_vec() {
+            // values of the book `TreeMap`
+            let mut book_values = LookupMap::<OrderId, Order>::new([pair.as_bytes(), b"v"].concat());
+            let old_book = LookupMap::<OrderId, OrderV2>::new([pair.as_bytes(), b"v"].concat());
+            let orders_map = self.orders.get(&pair).unwrap_or_else(|| env::panic_str(ERR01_INTERNAL));
+
+            // walks the keys only, reading the values would fail
+            let mut next = orders_map.min();
+            while let Some(order_id) = next {
+                next = orders_map.higher(&order_id);
+                let order = old_book.get(&order_id).unwrap_or_else(|| env::panic_str(ERR01_INTERNAL));
+                let key_raw = order_id.try_to_vec().unwrap_or_else(|_| env::abort());
+                let value_raw = Order::from(order).try_to_vec().unwrap_or_else(|_| env::abort());
+                book_values.insert_raw(&key_raw, &value_raw);
+                self.order_id_to_order.insert_raw(&key_raw, &value_raw);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::legacy::LegacyOrderId;
+    use near_sdk::test_utils::VMContextBuilder;
+    use near_sdk::testing_env;
+
+    fn order_v1(maker: &str, sell_amount: u128, buy_amount: u128) -> OrderV1 {
+        OrderV1 {
+            maker: maker.parse().unwrap(),
+            sell_token: "xabr.allbridge.testnet".parse().unwrap(),
+            sell_amount: U128(sell_amount),
+            buy_token: "abr.allbridge.testnet".parse().unwrap(),
+            buy_amount: U128(buy_amount),
+        }
+    }
+
+    fn migrate() -> Market {
+        testing_env!(VMContextBuilder::new()
+            .predecessor_account_id(env::current_account_id())
+            .build());
+        Market::migrate()
+    }
+
+    #[test]
+    fn test_migrate_from_v1() {
+        let orders = [order_v1("alice.near", 1000, 100), order_v1("bob.near", 3000, 100)];
+        let key = compose_key(&orders[0].sell_token, &orders[0].buy_token);
+        let token = orders[0].sell_token.clone();
+
+        // state as written by version 1
+        let mut old = MarketV1 {
+            version: 1,
+            orders: UnorderedMap::new(StorageKey::Orders),
+            order_id_to_order: LookupMap::new(StorageKey::OrderIdToOrder),
+            fees: LookupMap::new(StorageKey::FeesByAccountIds),
+        };
+        let legacy_ids = [LegacyOrderId(10u128.pow(28), 0xdead_beef), LegacyOrderId(3 * 10u128.pow(28), 0xfeed)];
+        let mut book = TreeMap::<LegacyOrderId, OrderV1>::new(key.as_bytes());
+        for (order_id, order) in legacy_ids.iter().zip(orders.iter()) {
+            book.insert(order_id, order);
+            old.order_id_to_order.insert(order_id, order);
+        }
+        let book = LegacyOrderBook::<OrderV1>::try_from_slice(&book.try_to_vec().unwrap()).unwrap();
+        old.orders.insert(&key, &book);
+        old.fees.insert(&token, &Fee::new(50, 1234));
+        env::state_write(&old);
+
+        let contract = migrate();
+        assert_eq!(contract.version, STATE_VERSION);
+
+        // best price first, the nonces follow the old order
+        let migrated: Vec<(OrderId, Order)> = contract.orders.get(&key).unwrap().iter().collect();
+        assert_eq!(
+            migrated,
+            vec![
+                (OrderId(Price::new(3000, 100), 1), Order::from(orders[1].clone())),
+                (OrderId(Price::new(1000, 100), 0), Order::from(orders[0].clone())),
+            ]
+        );
+        assert_eq!(contract.get_pairs(), vec![key]);
+        for (order_id, order) in migrated {
+            assert_eq!(contract.get_order(order_id), Some(order.clone()));
+            assert_eq!(
+                contract.get_orders_by_account(order.maker.clone(), None, None),
+                vec![OrderView { order_id, order }]
+            );
+        }
+        let legacy_lookup = LookupMap::<LegacyOrderId, OrderV1>::new(StorageKey::OrderIdToOrder);
+        for order_id in legacy_ids.iter() {
+            assert!(legacy_lookup.get(order_id).is_none());
+        }
+
+        let fee = contract.fees.get(&token).unwrap();
+        assert_eq!((fee.percent, fee.earned), (50, 1234));
+        assert_eq!(contract.get_token(token).unwrap().status, TokenStatus::Listed);
+        assert_eq!(contract.get_token(orders[0].buy_token.clone()).unwrap().status, TokenStatus::Listed);
+    }
+
+    #[test]
+    fn test_migrate_from_v2() {
+        let mut contract = Market::new(None);
+        let order = OrderV2 {
+            maker: "alice.near".parse().unwrap(),
+            sell_token: "xabr.allbridge.testnet".parse().unwrap(),
+            sell_amount: U128(700),
+            buy_token: "abr.allbridge.testnet".parse().unwrap(),
+            buy_amount: U128(70),
+            filled_sell_amount: U128(300),
+            filled_buy_amount: U128(30),
+            expires_at: Some(U64(42)),
+        };
+        let key = compose_key(&order.sell_token, &order.buy_token);
+        let order_id = OrderId(Price::new(700, 70), 3);
+
+        // state as written by version 2, orders without a status
+        let mut book = TreeMap::<OrderId, OrderV2>::new(key.as_bytes());
+        book.insert(&order_id, &order);
+        LookupMap::<OrderId, OrderV2>::new(StorageKey::OrderIdToOrder).insert(&order_id, &order);
+        contract.orders.insert(&key, &TreeMap::try_from_slice(&book.try_to_vec().unwrap()).unwrap());
+        contract.version = 2;
+        env::state_write(&contract);
+
+        let contract = migrate();
+        assert_eq!(contract.version, STATE_VERSION);
+
+        let expected = Order::from(order);
+        assert_eq!(expected.status, OrderStatus::Open);
+        assert_eq!(expected.filled_sell_amount, U128(300));
+        assert_eq!(expected.expires_at, Some(U64(42)));
+        assert_eq!(contract.get_order(order_id), Some(expected.clone()));
+        assert_eq!(
+            contract.orders.get(&key).unwrap().iter().collect::<Vec<_>>(),
+            vec![(order_id, expected)]
+        );
+    }
+
+    #[test]
+    fn test_migrate_current_version_keeps_state() {
+        let mut contract = Market::new(Some("owner.near".parse().unwrap()));
+        let token: AccountId = "xabr.allbridge.testnet".parse().unwrap();
+        contract.fees.insert(&token, &Fee::new(25, 0));
+        contract.next_order_nonce = 7;
+        env::state_write(&contract);
+
+        let contract = migrate();
+        assert_eq!(contract.version, STATE_VERSION);
+        assert_eq!(contract.get_owner(), "owner.near".parse::<AccountId>().unwrap());
+        assert_eq!(contract.next_order_nonce, 7);
+        assert_eq!(contract.fees.get(&token).unwrap().percent, 25);
+    }
+}
@@ -0,0 +1,74 @@
+//! Emergency stop for the whole market and for single pairs. Only new orders
+//! and matches are stopped, cancellations, withdrawals and settlements of
+//! fills already in flight keep working so users can always exit.
+
+use crate::access_control::Role;
+use crate::*;
+
+impl Market {
+    /// Whether trading between the tokens is stopped. A halted pair is
+    /// halted in both directions.
+    pub(crate) fn internal_is_halted(&self, token_a: &AccountId, token_b: &AccountId) -> bool {
+        self.paused
+            || self.halted_pairs.contains(&compose_key(token_a, token_b))
+            || self.halted_pairs.contains(&compose_key(token_b, token_a))
+    }
+
+    /// Whether the message of `ft_on_transfer` trades on a stopped pair.
+    pub(crate) fn internal_is_message_halted(&self, message: &TokenReceiverMessage, token: &AccountId) -> bool {
+        match message {
+            TokenReceiverMessage::NewOrderAction { sell_token, buy_token, .. } => {
+                self.internal_is_halted(sell_token, buy_token)
+            }
+            TokenReceiverMessage::Match { order_id } => match self.order_id_to_order.get(order_id) {
+                Some(order) => self.internal_is_halted(&order.sell_token, &order.buy_token),
+                None => self.paused,
+            },
+            TokenReceiverMessage::MarketBuy { buy_token, .. } => self.internal_is_halted(token, buy_token),
+        }
+    }
+}
+
+#[near_bindgen]
+impl Market {
+    #[payable]
+    pub fn pause(&mut self) {
+        assert_one_yocto();
+        self.assert_role(Role::Pauser);
+        self.paused = true;
+        env::log_str("market paused");
+    }
+
+    #[payable]
+    pub fn unpause(&mut self) {
+        assert_one_yocto();
+        self.assert_role(Role::Pauser);
+        self.paused = false;
+        env::log_str("market unpaused");
+    }
+
+    /// Stops trading in the `pair` ("sell#buy") and its reverse.
+    #[payable]
+    pub fn halt_pair(&mut self, pair: String) {
+        assert_one_yocto();
+        self.assert_role(Role::Pauser);
+        env::log_str(&format!("pair {} halted", pair));
+        self.halted_pairs.insert(&pair);
+    }
+
+    #[payable]
+    pub fn resume_pair(&mut self, pair: String) {
+        assert_one_yocto();
+        self.assert_role(Role::Pauser);
+        env::log_str(&format!("pair {} resumed", pair));
+        self.halted_pairs.remove(&pair);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    pub fn get_halted_pairs(&self) -> Vec<String> {
+        self.halted_pairs.to_vec()
+    }
+}
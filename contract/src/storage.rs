@@ -0,0 +1,190 @@
+use crate::*;
+use near_contract_standards::storage_management::{
+    StorageBalance, StorageBalanceBounds, StorageManagement,
+};
+use near_sdk::{Balance, Promise, StorageUsage};
+
+/// Bytes taken by the registration record itself, covered by the minimum balance.
+pub const ACCOUNT_STORAGE_BYTES: StorageUsage = 128;
+
+#[derive(BorshSerialize, BorshDeserialize)]
+pub struct AccountStorage {
+    pub deposit: Balance,
+    /// bytes taken by the account's orders
+    pub used: StorageUsage,
+}
+
+fn storage_balance_min() -> Balance {
+    ACCOUNT_STORAGE_BYTES as Balance * env::storage_byte_cost()
+}
+
+impl AccountStorage {
+    fn available(&self) -> Balance {
+        self.deposit
+            .saturating_sub(storage_balance_min())
+            .saturating_sub(self.used as Balance * env::storage_byte_cost())
+    }
+
+    fn to_storage_balance(&self) -> StorageBalance {
+        StorageBalance {
+            total: U128(self.deposit),
+            available: U128(self.available()),
+        }
+    }
+}
+
+#[near_bindgen]
+impl StorageManagement for Market {
+    #[payable]
+    fn storage_deposit(
+        &mut self,
+        account_id: Option<AccountId>,
+        registration_only: Option<bool>,
+    ) -> StorageBalance {
+        let amount = env::attached_deposit();
+        let account_id = account_id.unwrap_or_else(env::predecessor_account_id);
+        let registration_only = registration_only.unwrap_or(false);
+
+        let storage = match self.storage_accounts.get(&account_id) {
+            Some(mut storage) => {
+                if registration_only {
+                    if amount > 0 {
+                        Promise::new(env::predecessor_account_id()).transfer(amount);
+                    }
+                } else {
+                    storage.deposit += amount;
+                    self.storage_accounts.insert(&account_id, &storage);
+                }
+                storage
+            }
+            None => {
+                let min_balance = storage_balance_min();
+                if amount < min_balance {
+                    env::panic_str(ERR13_NOT_ENOUGH_STORAGE);
+                }
+
+                let deposit = if registration_only {
+                    let refund = amount - min_balance;
+                    if refund > 0 {
+                        Promise::new(env::predecessor_account_id()).transfer(refund);
+                    }
+                    min_balance
+                } else {
+                    amount
+                };
+
+                let storage = AccountStorage { deposit, used: 0 };
+                self.storage_accounts.insert(&account_id, &storage);
+                storage
+            }
+        };
+
+        storage.to_storage_balance()
+    }
+
+    #[payable]
+    fn storage_withdraw(&mut self, amount: Option<U128>) -> StorageBalance {
+        assert_one_yocto();
+        let account_id = env::predecessor_account_id();
+        let mut storage = self
+            .storage_accounts
+            .get(&account_id)
+            .unwrap_or_else(|| env::panic_str(ERR13_NOT_ENOUGH_STORAGE));
+
+        let available = storage.available();
+        let amount = amount.map(|amount| amount.0).unwrap_or(available);
+        if amount > available {
+            env::panic_str(ERR13_NOT_ENOUGH_STORAGE);
+        }
+
+        if amount > 0 {
+            storage.deposit -= amount;
+            self.storage_accounts.insert(&account_id, &storage);
+            Promise::new(account_id).transfer(amount);
+        }
+
+        storage.to_storage_balance()
+    }
+
+    /// Open orders and internal balances can't be burned, so `force` doesn't help
+    /// while the account still has any of them.
+    #[payable]
+    fn storage_unregister(&mut self, force: Option<bool>) -> bool {
+        assert_one_yocto();
+        let _ = force;
+        let account_id = env::predecessor_account_id();
+        let storage = match self.storage_accounts.get(&account_id) {
+            Some(storage) => storage,
+            None => return false,
+        };
+
+        if storage.used > 0 || self.balances.get(&account_id).is_some() {
+            env::panic_str(ERR14_STORAGE_IN_USE);
+        }
+
+        self.storage_accounts.remove(&account_id);
+        Promise::new(account_id).transfer(storage.deposit);
+        true
+    }
+
+    fn storage_balance_bounds(&self) -> StorageBalanceBounds {
+        StorageBalanceBounds {
+            min: U128(storage_balance_min()),
+            max: None,
+        }
+    }
+
+    fn storage_balance_of(&self, account_id: AccountId) -> Option<StorageBalance> {
+        self.storage_accounts
+            .get(&account_id)
+            .map(|storage| storage.to_storage_balance())
+    }
+}
+
+impl Market {
+    /// Charges `bytes` of newly used storage to the storage balance of the account.
+    pub(crate) fn internal_charge_storage(&mut self, account_id: &AccountId, bytes: StorageUsage) {
+        let mut storage = self
+            .storage_accounts
+            .get(account_id)
+            .unwrap_or_else(|| env::panic_str(ERR13_NOT_ENOUGH_STORAGE));
+
+        storage.used += bytes;
+        if storage.used as Balance * env::storage_byte_cost() + storage_balance_min() > storage.deposit {
+            env::panic_str(ERR13_NOT_ENOUGH_STORAGE);
+        }
+
+        self.storage_accounts.insert(account_id, &storage);
+    }
+
+    /// Gives back the `bytes` charged for an order of the account.
+    pub(crate) fn internal_release_storage(&mut self, account_id: &AccountId, bytes: StorageUsage) {
+        // orders placed before storage management was introduced were never charged
+        if bytes == 0 {
+            return;
+        }
+        let mut storage = self
+            .storage_accounts
+            .get(account_id)
+            .unwrap_or_else(|| env::panic_str(ERR01_INTERNAL));
+        storage.used = storage
+            .used
+            .checked_sub(bytes)
+            .unwrap_or_else(|| env::panic_str(ERR01_INTERNAL));
+        self.storage_accounts.insert(account_id, &storage);
+    }
+
+    /// Takes the deposit covering `bytes` of already released storage from the
+    /// account, used to reward whoever cleaned up the account's expired orders.
+    pub(crate) fn internal_take_storage_reward(&mut self, account_id: &AccountId, bytes: StorageUsage) -> Balance {
+        match self.storage_accounts.get(account_id) {
+            Some(mut storage) => {
+                let reward = std::cmp::min(bytes as Balance * env::storage_byte_cost(), storage.available());
+                storage.deposit -= reward;
+                self.storage_accounts.insert(account_id, &storage);
+                reward
+            }
+            None => 0,
+        }
+    }
+}
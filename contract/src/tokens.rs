@@ -0,0 +1,272 @@
+//! Registry of the tokens that can be traded. Tokens are listed by a token listing
+//! manager, or proposed by anyone with a NEAR bond while open listing is enabled.
+//! The NEP-148 metadata of listed tokens is cached to show normalized amounts.
+
+use crate::access_control::Role;
+use crate::*;
+use near_contract_standards::fungible_token::metadata::FungibleTokenMetadata;
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{require, Balance};
+
+pub const FT_METADATA_TGAS: Gas = Gas(10_000_000_000_000);
+/// More decimals don't fit a `u128` amount scaled by the unit of the other token into `U256`.
+pub const MAX_TOKEN_DECIMALS: u8 = 38;
+
+#[derive(Serialize, Deserialize, BorshSerialize, BorshDeserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub enum TokenStatus {
+    /// proposed with a bond, waits for approval
+    Proposed,
+    Listed,
+    /// no new orders or matches, existing orders can only be cancelled
+    Delisted,
+}
+
+#[derive(Serialize, Deserialize, BorshSerialize, BorshDeserialize, Clone, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct TokenInfo {
+    pub status: TokenStatus,
+    /// account that posted the bond of a proposed token
+    pub proposer: Option<AccountId>,
+    pub bond: U128,
+}
+
+/// Part of the NEP-148 metadata needed to show amounts. The icon is kept as
+/// the hex encoded sha256 of the data URL, enough to tell it changed.
+#[derive(Serialize, Deserialize, BorshSerialize, BorshDeserialize, Clone, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct TokenMetadata {
+    pub symbol: String,
+    pub decimals: u8,
+    pub icon_hash: Option<String>,
+}
+
+/// An order with amounts in whole tokens. `price` is the buy token asked for one sell token.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct NormalizedOrderView {
+    pub order_id: OrderId,
+    pub maker: AccountId,
+    pub sell_symbol: String,
+    pub sell_amount: String,
+    pub buy_symbol: String,
+    pub buy_amount: String,
+    pub price: String,
+}
+
+impl Market {
+    pub(crate) fn is_token_listed(&self, token: &AccountId) -> bool {
+        self.tokens
+            .get(token)
+            .is_some_and(|info| info.status == TokenStatus::Listed)
+    }
+
+    pub(crate) fn assert_token_listed(&self, token: &AccountId) {
+        require!(self.is_token_listed(token), ERR17_TOKEN_NOT_LISTED);
+    }
+
+    pub(crate) fn internal_set_token_status(&mut self, token: &AccountId, status: TokenStatus) {
+        let info = TokenInfo { status, proposer: None, bond: U128(0) };
+        self.tokens.insert(token, &info);
+        env::log_str(&format!("token {} status: {:?}", token, status));
+    }
+
+    /// Takes the bond off a proposed token.
+    fn internal_take_bond(&mut self, token: &AccountId) -> (AccountId, Balance) {
+        let info = self.tokens.get(token).unwrap_or_else(|| env::panic_str(ERR17_TOKEN_NOT_LISTED));
+        if info.status != TokenStatus::Proposed {
+            env::panic_str(ERR18_TOKEN_NOT_PROPOSED);
+        }
+        let proposer = info.proposer.unwrap_or_else(|| env::panic_str(ERR01_INTERNAL));
+        (proposer, info.bond.0)
+    }
+}
+
+#[near_bindgen]
+impl Market {
+    /// Lists the token, a proposed one is approved.
+    #[payable]
+    pub fn list_token(&mut self, token: AccountId) {
+        assert_one_yocto();
+        self.assert_role(Role::TokenListingManager);
+        if self.tokens.get(&token).is_some_and(|info| info.status == TokenStatus::Proposed) {
+            let (proposer, bond) = self.internal_take_bond(&token);
+            Promise::new(proposer).transfer(bond);
+        }
+        self.internal_set_token_status(&token, TokenStatus::Listed);
+    }
+
+    #[payable]
+    pub fn delist_token(&mut self, token: AccountId) {
+        assert_one_yocto();
+        self.assert_role(Role::TokenListingManager);
+        let info = self.tokens.get(&token).unwrap_or_else(|| env::panic_str(ERR17_TOKEN_NOT_LISTED));
+        // delisting would drop the bond, it's either refunded or slashed
+        require!(info.status != TokenStatus::Proposed, ERR25_TOKEN_PROPOSED);
+        self.internal_set_token_status(&token, TokenStatus::Delisted);
+    }
+
+    /// Enables open listing with the given bond, or disables it with `None`.
+    #[payable]
+    pub fn set_listing_bond(&mut self, bond: Option<U128>) {
+        assert_one_yocto();
+        self.assert_role(Role::TokenListingManager);
+        self.listing_bond = bond.map(|bond| bond.0);
+    }
+
+    /// Proposes a new token while open listing is enabled, the attached deposit
+    /// is the bond and has to cover `get_listing_bond`.
+    #[payable]
+    pub fn propose_token(&mut self, token: AccountId) {
+        let bond = self.listing_bond.unwrap_or_else(|| env::panic_str(ERR19_OPEN_LISTING_DISABLED));
+        let deposit = env::attached_deposit();
+        require!(deposit >= bond, ERR19_OPEN_LISTING_DISABLED);
+        if self.tokens.get(&token).is_some() {
+            env::panic_str(ERR20_TOKEN_ALREADY_REGISTERED);
+        }
+
+        let proposer = env::predecessor_account_id();
+        env::log_str(&format!("token {} proposed by {}", token, proposer));
+        self.tokens.insert(
+            &token,
+            &TokenInfo { status: TokenStatus::Proposed, proposer: Some(proposer), bond: U128(deposit) },
+        );
+    }
+
+    /// Lists a proposed token and returns the bond to the proposer.
+    #[payable]
+    pub fn approve_token(&mut self, token: AccountId) -> Promise {
+        assert_one_yocto();
+        self.assert_role(Role::TokenListingManager);
+        let (proposer, bond) = self.internal_take_bond(&token);
+        self.internal_set_token_status(&token, TokenStatus::Listed);
+        Promise::new(proposer).transfer(bond)
+    }
+
+    /// Rejects a proposed token, its bond goes to the owner.
+    #[payable]
+    pub fn slash_token(&mut self, token: AccountId) -> Promise {
+        assert_one_yocto();
+        self.assert_role(Role::TokenListingManager);
+        let (proposer, bond) = self.internal_take_bond(&token);
+        env::log_str(&format!("token {} rejected, bond of {} slashed", token, proposer));
+        self.tokens.remove(&token);
+        Promise::new(self.owner_id.clone()).transfer(bond)
+    }
+
+    pub fn get_token(&self, token: AccountId) -> Option<TokenInfo> {
+        self.tokens.get(&token)
+    }
+
+    pub fn get_tokens(&self, from_index: Option<u64>, limit: Option<u64>) -> Vec<(AccountId, TokenInfo)> {
+        let keys = self.tokens.keys_as_vector();
+        let values = self.tokens.values_as_vector();
+        let from_index = from_index.unwrap_or(0);
+        let limit = limit.unwrap_or(DEFAULT_ORDERS_LIMIT);
+
+        (from_index..std::cmp::min(from_index.saturating_add(limit), keys.len()))
+            .filter_map(|index| keys.get(index).zip(values.get(index)))
+            .collect()
+    }
+
+    pub fn get_listing_bond(&self) -> Option<U128> {
+        self.listing_bond.map(U128)
+    }
+
+    /// Fetches `ft_metadata` of a listed token and caches it, again if it changed.
+    pub fn register_token(&mut self, token: AccountId) -> Promise {
+        self.assert_token_listed(&token);
+
+        ft_token::ft_metadata(token.clone(), 0, FT_METADATA_TGAS).then(ext_self::callback_on_ft_metadata(
+            token,
+            env::current_account_id(),
+            0,
+            RESERVE_TGAS,
+        ))
+    }
+
+    #[private]
+    pub fn callback_on_ft_metadata(&mut self, token: AccountId) -> bool {
+        assert_eq!(
+            env::promise_results_count(),
+            1,
+            "{}",
+            ERR08_NOT_CORRECT_PROMISE_RESULT_COUNT
+        );
+
+        let metadata = match env::promise_result(0) {
+            PromiseResult::Successful(result) => serde_json::from_slice::<FungibleTokenMetadata>(&result).ok(),
+            _ => None,
+        };
+        let metadata = match metadata {
+            Some(metadata) => metadata,
+            None => {
+                env::log_str(&format!("failed to fetch the metadata of {}", token));
+                return false;
+            }
+        };
+        if metadata.decimals > MAX_TOKEN_DECIMALS {
+            env::log_str(&format!(
+                "{} has {} decimals, at most {} are supported",
+                token, metadata.decimals, MAX_TOKEN_DECIMALS
+            ));
+            return false;
+        }
+
+        self.token_metadata.insert(
+            &token,
+            &TokenMetadata {
+                symbol: metadata.symbol,
+                decimals: metadata.decimals,
+                icon_hash: metadata.icon.map(|icon| hex::encode(env::sha256(icon.as_bytes()))),
+            },
+        );
+        true
+    }
+
+    pub fn get_token_metadata(&self, token: AccountId) -> Option<TokenMetadata> {
+        self.token_metadata.get(&token)
+    }
+
+    /// `get_orders` in whole tokens, `None` until both tokens are registered.
+    pub fn get_orders_normalized(
+        &self,
+        sell_token: AccountId,
+        buy_token: AccountId,
+        from_index: Option<OrderId>,
+        limit: Option<u64>,
+    ) -> Option<Vec<NormalizedOrderView>> {
+        let sell_metadata = self.token_metadata.get(&sell_token)?;
+        let buy_metadata = self.token_metadata.get(&buy_token)?;
+        let orders = self.get_orders(sell_token, buy_token, from_index, limit)?;
+
+        let sell_unit = U256::exp10(sell_metadata.decimals as usize);
+        let buy_unit = U256::exp10(buy_metadata.decimals as usize);
+        Some(
+            orders
+                .into_iter()
+                .map(|OrderView { order, order_id }| NormalizedOrderView {
+                    order_id,
+                    sell_symbol: sell_metadata.symbol.clone(),
+                    sell_amount: format_decimal(
+                        U256::from(order.sell_amount.0),
+                        sell_unit,
+                        sell_metadata.decimals as u32,
+                    ),
+                    buy_symbol: buy_metadata.symbol.clone(),
+                    buy_amount: format_decimal(
+                        U256::from(order.buy_amount.0),
+                        buy_unit,
+                        buy_metadata.decimals as u32,
+                    ),
+                    price: format_decimal(
+                        U256::from(order_id.0.buy_amount.0) * sell_unit,
+                        U256::from(order_id.0.sell_amount.0) * buy_unit,
+                        PRICE_DECIMALS,
+                    ),
+                    maker: order.maker,
+                })
+                .collect(),
+        )
+    }
+}
@@ -2,14 +2,14 @@ use std::cmp::Ordering;
 use near_sdk::{
     borsh,
     borsh::{BorshDeserialize, BorshSerialize},
+    env,
     json_types::{U128, U64},
     serde::{Deserialize, Serialize},
-    AccountId,
+    AccountId, StorageUsage,
 };
-use std::collections::hash_map::DefaultHasher;
+use crate::errors::ERR05_NOT_VALID_AMOUNT;
+use crate::helpers::{gcd, mul_div, mul_div_ceil, U256};
 use std::fmt::{Display, Formatter};
-use std::hash::{Hash, Hasher};
-use std::io::Write;
 
 // #[derive(Serialize, Deserialize, Clone, PartialEq)]
 // #[serde(crate = "near_sdk::serde")]
@@ -31,6 +31,12 @@ pub enum TokenReceiverMessage {
         sell_amount: U128,
         buy_token: AccountId,
         buy_amount: U128,
+        expires_at: Option<U64>,
+    },
+    /// Spends the whole deposit on the best `buy_token` offers, refunds what is left.
+    MarketBuy {
+        buy_token: AccountId,
+        min_receive: U128,
     }
 }
 
@@ -41,6 +47,26 @@ pub struct NewOrderAction {
     pub sell_amount: U128,
     pub buy_token: AccountId,
     pub buy_amount: U128,
+    /// block timestamp in nanoseconds after which the order can't be matched
+    pub expires_at: Option<U64>,
+}
+
+/// Part of a resting order taken by a taker: the taker pays `buy_amount`
+/// of the order's buy token and gets `sell_amount` of its sell token.
+pub struct Fill {
+    pub order_id: OrderId,
+    pub order: Order,
+    pub sell_amount: u128,
+    pub buy_amount: u128,
+}
+
+/// A `Fill` as passed to `callback_on_settle_fills`.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct FillView {
+    pub order_id: OrderId,
+    pub sell_amount: U128,
+    pub buy_amount: U128,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
@@ -50,6 +76,61 @@ pub struct OrderView {
     pub order_id: OrderId,
 }
 
+/// Orders of one side of the book with the same price. `price` is in quote
+/// token per base token, `quantity` is in the base token.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PriceLevel {
+    pub price: String,
+    pub quantity: U128,
+    pub orders: u64,
+}
+
+/// Bids buy the base token for the quote one, asks sell it, both best price first.
+/// The spread is `best_ask - best_bid` and is negative if the book is crossed.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct OrderBookView {
+    pub bids: Vec<PriceLevel>,
+    pub asks: Vec<PriceLevel>,
+    pub best_bid: Option<String>,
+    pub best_ask: Option<String>,
+    pub spread: Option<String>,
+}
+
+/// Progress of `cleanup_expired`. The scan continues from `next_index`,
+/// which is `None` once the end of the book is reached.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct CleanupResult {
+    pub removed: u64,
+    pub next_index: Option<OrderId>,
+}
+
+/// Progress of a batch cancel. `remaining` orders didn't fit into the gas
+/// of the call and have to be cancelled with another one.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct CancelOrdersResult {
+    pub cancelled: Vec<OrderId>,
+    pub remaining: u64,
+}
+
+/// Lifecycle of an order. Orders leave the book once `Filled` or `Cancelled`,
+/// so those two are only seen in the `status` of the order events.
+#[derive(Serialize, Deserialize, BorshSerialize, BorshDeserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub enum OrderStatus {
+    Open,
+    /// a fill is being paid out through transfers, the order can't be matched
+    /// or cancelled until its callbacks are done
+    Settling,
+    Filled,
+    Cancelled,
+}
+
+/// `sell_amount` and `buy_amount` hold what is still left to trade, the
+/// `filled_*` fields accumulate what was already traded by partial fills.
 #[derive(Serialize, Deserialize, BorshSerialize, BorshDeserialize, Clone, Debug, PartialEq)]
 #[serde(crate = "near_sdk::serde")]
 pub struct Order {
@@ -58,29 +139,18 @@ pub struct Order {
     pub sell_amount: U128,
     pub buy_token: AccountId,
     pub buy_amount: U128,
-}
-
-impl Hash for Order {
-    fn hash<H: Hasher>(&self, state: &mut H) {
-        self.maker.hash(state);
-        self.sell_token.hash(state);
-        self.sell_amount.0.hash(state);
-        self.buy_token.hash(state);
-        self.buy_amount.0.hash(state);
-    }
+    pub filled_sell_amount: U128,
+    pub filled_buy_amount: U128,
+    pub expires_at: Option<U64>,
+    pub status: OrderStatus,
+    /// storage charged to the maker when the order was placed, released when it's removed
+    #[serde(skip)]
+    pub storage_bytes: StorageUsage,
 }
 
 impl Order {
-    pub fn get_price_for_key(&self) -> u128 {
-        (self.sell_amount.0 + 1000000000000000000000000000000) / self.buy_amount.0
-    }
-
-    pub fn get_id(&self) -> OrderId {
-        let mut hasher = DefaultHasher::new();
-        self.hash(&mut hasher);
-        let hash = hasher.finish();
-
-        OrderId(self.get_price_for_key(), hash)
+    pub fn get_price(&self) -> Price {
+        Price::new(self.sell_amount.0, self.buy_amount.0)
     }
 
     pub fn from_action(action: NewOrderAction, sender: AccountId) -> Self {
@@ -90,35 +160,136 @@ impl Order {
             sell_amount: action.sell_amount,
             buy_token: action.buy_token,
             buy_amount: action.buy_amount,
+            filled_sell_amount: U128(0),
+            filled_buy_amount: U128(0),
+            expires_at: action.expires_at,
+            status: OrderStatus::Open,
+            storage_bytes: 0,
         }
     }
+
+    /// Pro-rata part of the remaining `sell_amount` for `buy_amount` of the buy token.
+    /// Rounded down, so the leftover always stays on the maker's side.
+    pub fn get_sell_amount_for(&self, buy_amount: u128) -> u128 {
+        mul_div(buy_amount, self.sell_amount.0, self.buy_amount.0)
+    }
+
+    pub fn fill(&mut self, sell_amount: u128, buy_amount: u128) {
+        self.sell_amount = U128(
+            self.sell_amount.0
+                .checked_sub(sell_amount)
+                .unwrap_or_else(|| env::panic_str(ERR05_NOT_VALID_AMOUNT))
+        );
+        self.buy_amount = U128(
+            self.buy_amount.0
+                .checked_sub(buy_amount)
+                .unwrap_or_else(|| env::panic_str(ERR05_NOT_VALID_AMOUNT))
+        );
+        self.filled_sell_amount = U128(self.filled_sell_amount.0 + sell_amount);
+        self.filled_buy_amount = U128(self.filled_buy_amount.0 + buy_amount);
+    }
+
+    /// Fill of a just placed order against the book. It may trade at a better price
+    /// than it asked for, so the remainder is re-priced at the order's own limit price.
+    /// Rounded up, the remainder never asks for less than the limit price.
+    pub fn take(&mut self, sell_amount: u128, buy_amount: u128) {
+        let remaining_sell = self.sell_amount.0
+            .checked_sub(sell_amount)
+            .unwrap_or_else(|| env::panic_str(ERR05_NOT_VALID_AMOUNT));
+        let remaining_buy = mul_div_ceil(remaining_sell, self.buy_amount.0, self.sell_amount.0);
+
+        self.sell_amount = U128(remaining_sell);
+        self.buy_amount = U128(remaining_buy);
+        self.filled_sell_amount = U128(self.filled_sell_amount.0 + sell_amount);
+        self.filled_buy_amount = U128(self.filled_buy_amount.0 + buy_amount);
+    }
+
+    /// Whether the resting `other` order (selling what this one buys) offers
+    /// a price equal to or better than this order's limit price.
+    pub fn crosses(&self, other: &Order) -> bool {
+        U256::from(other.sell_amount.0) * U256::from(self.sell_amount.0)
+            >= U256::from(self.buy_amount.0) * U256::from(other.buy_amount.0)
+    }
+
+    pub fn is_filled(&self) -> bool {
+        self.sell_amount.0 == 0 || self.buy_amount.0 == 0
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.status == OrderStatus::Open
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.expires_at
+            .is_some_and(|expires_at| env::block_timestamp() >= expires_at.0)
+    }
 }
 
 
-#[derive(Debug, Ord, PartialEq, Clone, Copy, BorshSerialize, BorshDeserialize, Serialize, Deserialize)]
+/// Exact `sell_amount / buy_amount` ratio of an order, reduced so that
+/// equal prices have equal representations.
+///
+/// Better prices for the taker (more sell token per buy token) come first.
+#[derive(Debug, PartialEq, Clone, Copy, BorshSerialize, BorshDeserialize, Serialize, Deserialize)]
 #[serde(crate = "near_sdk::serde")]
-pub struct OrderId(pub u128, pub u64);
+pub struct Price {
+    pub sell_amount: U128,
+    pub buy_amount: U128,
+}
 
-impl OrderId {
-    pub fn from_order(order: &Order) -> Self {
-        let mut hasher = DefaultHasher::new();
-        order.hash(&mut hasher);
+impl Price {
+    pub fn new(sell_amount: u128, buy_amount: u128) -> Self {
+        let divisor = match gcd(sell_amount, buy_amount) {
+            0 => 1,
+            divisor => divisor,
+        };
 
-        Self(order.get_price_for_key(), hasher.finish())
+        Self {
+            sell_amount: U128(sell_amount / divisor),
+            buy_amount: U128(buy_amount / divisor),
+        }
     }
 }
 
-impl Eq for OrderId {}
+impl Eq for Price {}
+
+impl Ord for Price {
+    fn cmp(&self, other: &Self) -> Ordering {
+        let this = U256::from(self.sell_amount.0) * U256::from(other.buy_amount.0);
+        let that = U256::from(other.sell_amount.0) * U256::from(self.buy_amount.0);
+        that.cmp(&this)
+    }
+}
 
-impl PartialOrd for OrderId {
+impl PartialOrd for Price {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        self.0.partial_cmp(&other.0)
+        Some(self.cmp(other))
+    }
+}
+
+impl Display for Price {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.sell_amount.0, self.buy_amount.0)
+    }
+}
+
+/// Orders by price first and by nonce between orders with the same price,
+/// so the book is best price first and earlier orders first within a price.
+#[derive(Debug, Ord, PartialOrd, Eq, PartialEq, Clone, Copy, BorshSerialize, BorshDeserialize, Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct OrderId(pub Price, pub u64);
+
+impl OrderId {
+    /// `nonce` comes from the market's order counter, so the id is unique
+    /// even for identical orders and doesn't depend on the compiler version.
+    pub fn new(order: &Order, nonce: u64) -> Self {
+        Self(order.get_price(), nonce)
     }
 }
 
 impl Display for OrderId {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}{}", self.0, self.1)
+        write!(f, "{}#{}", self.0, self.1)
     }
 }
 
@@ -132,4 +303,138 @@ impl Fee {
     pub fn new(percent: u16, earned: u128) -> Self {
         Self { percent, earned }
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use near_sdk::collections::TreeMap;
+    use near_sdk::test_utils::VMContextBuilder;
+    use near_sdk::testing_env;
+
+    /// xorshift, enough to spread the amounts over all magnitudes
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn amount(&mut self) -> u128 {
+            let amount = ((self.next() as u128) << 64 | self.next() as u128) >> (self.next() % 128);
+            amount.max(1)
+        }
+
+        fn order_id(&mut self) -> OrderId {
+            // small nonces so equal prices often meet equal nonces too
+            OrderId(Price::new(self.amount(), self.amount()), self.next() % 4)
+        }
+    }
+
+    #[test]
+    fn test_price_is_exact() {
+        // both map to the same key with `(sell + 10^30) / buy`
+        assert!(Price::new(10u128.pow(24), 1) < Price::new(10u128.pow(24) - 1, 1));
+        // different decimals, same ratio
+        assert_eq!(Price::new(10u128.pow(24), 10u128.pow(6)), Price::new(10u128.pow(18), 1));
+        assert_eq!(Price::new(u128::MAX, u128::MAX), Price::new(1, 1));
+        assert!(Price::new(u128::MAX - 1, u128::MAX - 2) < Price::new(u128::MAX, u128::MAX - 1));
+
+        let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+        for _ in 0..1000 {
+            let (a, b, c, d) = (rng.next() as u128, rng.next() as u128, rng.next() as u128, rng.next() as u128);
+            let (a, b, c, d) = (a.max(1), b.max(1), c.max(1), d.max(1));
+            // better for the taker, more sell per buy, comes first
+            assert_eq!(Price::new(a, b).cmp(&Price::new(c, d)), (c * b).cmp(&(a * d)));
+        }
+    }
+
+    #[test]
+    fn test_taken_remainder_keeps_the_limit_price() {
+        testing_env!(VMContextBuilder::new().build());
+        let mut rng = Rng(0x1234_5678_9abc_def1);
+        for _ in 0..1000 {
+            let (sell_amount, buy_amount) = (rng.amount(), rng.amount());
+            let mut order = Order::from_action(
+                NewOrderAction {
+                    sell_token: "a.near".parse().unwrap(),
+                    sell_amount: U128(sell_amount),
+                    buy_token: "b.near".parse().unwrap(),
+                    buy_amount: U128(buy_amount),
+                    expires_at: None,
+                },
+                "maker.near".parse().unwrap(),
+            );
+            let taken = rng.amount() % sell_amount;
+            order.take(taken, rng.amount());
+
+            // remaining buy / remaining sell is at least buy / sell
+            assert!(
+                U256::from(order.buy_amount.0) * U256::from(sell_amount)
+                    >= U256::from(buy_amount) * U256::from(order.sell_amount.0)
+            );
+        }
+    }
+
+    #[test]
+    fn test_order_id_order_is_total() {
+        let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+        let ids: Vec<OrderId> = (0..60).map(|_| rng.order_id()).collect();
+
+        for a in ids.iter() {
+            for b in ids.iter() {
+                assert_eq!(a.cmp(b), b.cmp(a).reverse());
+                assert_eq!(a.partial_cmp(b), Some(a.cmp(b)));
+                assert_eq!(a == b, a.cmp(b) == Ordering::Equal);
+
+                for c in ids.iter() {
+                    if a <= b && b <= c {
+                        assert!(a <= c);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_tree_map_order_is_consistent() {
+        testing_env!(VMContextBuilder::new().build());
+        let mut rng = Rng(0xdead_beef_cafe_f00d);
+        let mut tree = TreeMap::new(b"t".to_vec());
+        let mut ids = vec![];
+
+        for nonce in 0..300 {
+            // fresh gas for every insert, the storage stays
+            testing_env!(VMContextBuilder::new().build());
+            let price = if nonce % 3 == 0 && !ids.is_empty() {
+                // same price as an earlier order, in other amounts
+                let OrderId(price, _): OrderId = ids[rng.next() as usize % ids.len()];
+                let scale = 1 + rng.next() as u128 % 1000;
+                Price::new(
+                    price.sell_amount.0.saturating_mul(scale),
+                    price.buy_amount.0.saturating_mul(scale),
+                )
+            } else {
+                Price::new(rng.amount(), rng.amount())
+            };
+            let order_id = OrderId(price, nonce);
+            tree.insert(&order_id, &nonce);
+            ids.push(order_id);
+        }
+
+        ids.sort();
+        testing_env!(VMContextBuilder::new().build());
+        let keys: Vec<OrderId> = tree.iter().map(|(order_id, _)| order_id).collect();
+        assert_eq!(keys, ids);
+
+        for pair in keys.windows(2) {
+            assert!(pair[0].0 <= pair[1].0);
+            if pair[0].0 == pair[1].0 {
+                // time priority within a price
+                assert!(pair[0].1 < pair[1].1);
+            }
+        }
+    }
+}
@@ -54,6 +54,20 @@ export class BotApi {
                 const buy_amount = await this.api.fromPrecision(buyAmount, USDT_TOKEN_ADDRESS);
                 const buy_token = USDT_TOKEN_ADDRESS;
 
+                // the market charges the resting order to the maker's storage balance
+                const user = await this.getUser(chatId);
+                const storageDeposit = await this.api.getOrderStorageDeposit(CONTRACT, user.accountId);
+                const depositAddresses: { depositContract: string, depositAddress: string, depositAmount?: string }[] = [
+                    { depositContract: sell_token, depositAddress: CONTRACT },
+                ];
+                if (storageDeposit) {
+                    depositAddresses.push({
+                        depositContract: CONTRACT,
+                        depositAddress: user.accountId,
+                        depositAmount: storageDeposit,
+                    });
+                }
+
                 await this.sendTransaction(
                     Number(chatId),
                     sell_token,
@@ -69,7 +83,7 @@ export class BotApi {
                         }),
                     },
                     // @ts-ignore
-                    [{ depositContract: sell_token, depositAddress: CONTRACT }],
+                    depositAddresses,
                     undefined,
                     `
       
@@ -127,7 +141,7 @@ export class BotApi {
         return user;
     }
 
-    async getOrder(orderId: string) {
+    async getOrder(orderId: any) {
         return this.api.contractQuery(CONTRACT, "get_order", { order_id: orderId });
     }
 
@@ -148,7 +162,7 @@ export class BotApi {
                             buy_amount,
                             buy_token
                         )} ${(await this.api.getTokenMeta(buy_token)).symbol}`,
-                    callback_data: `match ${order_id}`,
+                    callback_data: `match ${this.setOrderMap(order_id)}`,
                 },
             ]);
         }
@@ -199,6 +213,13 @@ export class BotApi {
         return hash;
     }
 
+    // Order ids are `[price, nonce]` tuples, too long for the 64 byte callback_data.
+    setOrderMap(orderId: any) {
+        const hash = crypto.createHash("md5").update(JSON.stringify(orderId)).digest("hex");
+        DataStorage.orderMap[hash] = orderId;
+        return hash;
+    }
+
     loginUrl(chatId: number) {
         const newUrl = new URL("login", `https://wallet.${NETWORK}.near.org`);
         newUrl.searchParams.set("success_url", `${SERVER_URL}/${chatId}/login`);
@@ -25,6 +25,7 @@ export class DataStorage {
     private sellMeta: { [chatId: string]: { [metaId: string]: SellMeta } } = {};
     private answerCallbacks: { [chatId: number]: Function } = {};
     public static pairMap: { [hash: string]: string } = {};
+    public static orderMap: { [hash: string]: any } = {};
 
     getSellMeta(chatId: string, metaId: string): SellMeta {
         return this.sellMeta[chatId][metaId];
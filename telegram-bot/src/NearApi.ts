@@ -8,6 +8,9 @@ import BN from 'bn.js';
 import { URL } from 'url';
 import { DataStorage, TokenMeta, UserMeta } from './DataStorage';
 
+// storage of one resting order on the market, 1000 bytes at 10^19 yocto per byte
+const ORDER_STORAGE_DEPOSIT = Big("10000000000000000000000");
+
 export interface TokenBalance {
     symbol: string;
     balance: string;
@@ -75,20 +78,21 @@ export class NearApi {
 
         const txs = [];
         let nonce = 1;
-        for (const { depositContract, depositAddress } of depositAddresses) {
-            if (await this.needToDeposit(depositContract, depositAddress)) {
-                const depositAmount = await this.getDepostiAmount(depositContract);
+        for (const { depositContract, depositAddress, depositAmount } of depositAddresses) {
+            // a given amount tops up the storage balance, otherwise the account is only registered
+            if (depositAmount || await this.needToDeposit(depositContract, depositAddress)) {
+                const amount = depositAmount || await this.getDepostiAmount(depositContract);
                 const depositActions = [
                     nearApi.transactions.functionCall(
                         "storage_deposit",
                         Buffer.from(
                             JSON.stringify({
-                                registration_only: true,
+                                registration_only: !depositAmount,
                                 account_id: depositAddress,
                             })
                         ),
                         new BN(gas),
-                        depositAmount
+                        new BN(amount)
                     ),
                 ];
                 txs.push(
@@ -148,6 +152,19 @@ export class NearApi {
         return !balance;
     }
 
+    // storage deposit the market still needs from the account to rest one more order, null if it has enough
+    async getOrderStorageDeposit(market: string, accountId: string): Promise<string | null> {
+        const balance = await this.contractQuery(market, "storage_balance_of", {
+            account_id: accountId,
+        });
+        if (!balance) {
+            const bounds = await this.contractQuery(market, "storage_balance_bounds", {});
+            return Big(bounds.min).plus(ORDER_STORAGE_DEPOSIT).toFixed();
+        }
+        const available = Big(balance.available);
+        return available.lt(ORDER_STORAGE_DEPOSIT) ? ORDER_STORAGE_DEPOSIT.minus(available).toFixed() : null;
+    }
+
     async getDepostiAmount(contract: string) {
         const result = await this.contractQuery(contract, "storage_balance_bounds", {});
         return result.min;
@@ -14,6 +14,8 @@ import { NearApi } from './NearApi';
 import { DataStorage } from './DataStorage';
 import { BotApi } from './BotApi';
 
+const ORDERS_PAGE_SIZE = 20;
+
 const storage = new DataStorage();
 const tgBot = new TelegramBot(BOT_TOKEN, { polling: true });
 const api = new NearApi(PROVIDER, storage);
@@ -87,6 +89,8 @@ tgBot.on("callback_query", async function callback(callBackQuery) {
             const result = await api.contractQuery(CONTRACT, "get_orders", {
                 sell_token: sellToken,
                 buy_token: buyToken,
+                from_index: null,
+                limit: ORDERS_PAGE_SIZE,
             });
             if (!result || !result.length) {
                 tgBot.sendMessage(chatId, "No orders");
@@ -96,7 +100,11 @@ tgBot.on("callback_query", async function callback(callBackQuery) {
             break;
         }
         case "match": {
-            const order_id = p1;
+            const order_id = DataStorage.orderMap[p1];
+            if (!order_id) {
+                tgBot.sendMessage(chatId, "Order list is outdated, please request it again");
+                break;
+            }
             const order = await bot.getOrder(order_id);
             const { buy_token, buy_amount } = order;
             const user = await bot.getUser(String(chatId));